@@ -1,22 +1,145 @@
 import {
+  apng_add_frame,
+  apng_create,
+  apng_destroy,
+  apng_finish,
   draw_image_at,
   draw_image_with_size_at,
+  DrawCommand,
+  dt_apply_lut,
+  dt_apply_perspective_warp,
+  dt_auto_levels,
+  dt_blend_mode_fill_rect,
+  dt_canvas_to_data_url,
   dt_clear,
+  dt_clip_depth,
+  dt_color_balance,
+  dt_convert_to_linear,
+  dt_convert_to_srgb,
+  dt_convolve,
+  dt_count,
   dt_destroy,
+  dt_dilate,
+  dt_draw_arrow,
+  dt_draw_cached_image_at,
+  dt_draw_cached_image_with_size_at,
+  dt_draw_checkerboard,
+  dt_draw_grid,
+  dt_draw_image_at_subpixel,
+  dt_draw_image_batch,
+  dt_draw_image_fit,
+  dt_draw_image_with_color_key,
+  dt_draw_image_with_opacity,
+  dt_draw_mipmap_at,
+  dt_draw_progress_bar,
+  dt_draw_regular_polygon,
+  dt_draw_snapshot_at,
+  dt_draw_star_polygon,
+  dt_draw_svg_at,
+  dt_draw_svg_string,
+  dt_draw_text_on_path,
+  dt_emboss,
   dt_encode,
+  dt_encode_ico,
+  dt_encode_png_alpha_only,
+  dt_encode_png_grayscale,
+  dt_encode_png_optimized,
+  dt_encode_png_with_level,
+  dt_encode_qoi,
+  dt_encode_tiff,
+  dt_encode_with_icc_profile,
+  dt_erode,
+  dt_execute_command_list,
+  dt_expand_canvas,
   dt_fill,
+  dt_fill_arc,
+  dt_fill_donut,
+  dt_fill_gradient_rect,
+  dt_fill_path_batch,
   dt_fill_rect,
+  dt_fill_rect_batch,
+  dt_fill_text,
+  dt_fill_text_wrapped,
+  dt_fill_with_gradient,
+  dt_find_edges,
+  dt_flip_horizontal,
+  dt_flip_vertical,
+  dt_generate_mipmap_chain,
+  dt_generate_qr_code,
+  dt_get_average_color,
+  dt_get_channel,
   dt_get_data,
+  dt_get_dominant_color,
+  dt_get_histogram,
+  dt_get_text_path,
+  dt_height,
+  dt_layer_depth,
+  dt_list,
+  dt_mask_with_target,
+  dt_measure_text,
+  dt_median_filter,
+  dt_metadata_get,
+  dt_metadata_get_all,
+  dt_metadata_set,
+  dt_nine_slice_fill,
+  dt_opacity,
+  dt_path_as_clip_region,
+  dt_pixelate,
+  dt_pop_all_clips,
+  dt_pop_all_layers,
   dt_pop_clip,
   dt_pop_layer,
+  dt_posterize,
+  dt_premultiply,
   dt_push_clip,
   dt_push_clip_rect,
+  dt_push_clip_rect_float,
   dt_push_layer,
   dt_push_layer_with_blend,
+  dt_render_shadow_map,
+  dt_render_to_pdf_page,
+  dt_resize,
+  dt_rotate_180,
+  dt_rotate_270,
+  dt_rotate_90,
+  dt_scale_lanczos,
+  dt_scan_flood_fill,
+  dt_set_channel,
   dt_set_transform,
+  dt_sharpen,
+  dt_snapshot,
   dt_stroke,
+  dt_stroke_arc,
+  dt_stroke_path_batch,
+  dt_stroke_text,
+  dt_stroke_with_gradient,
+  dt_svg_path_as_clip_region,
+  dt_swap,
+  dt_swirl,
+  dt_tint,
+  dt_trim_transparent,
+  dt_unpremultiply,
+  dt_wave_distortion,
+  dt_width,
   dt_write_png,
-  new_draw_target,
+  FillCommand,
+  FillRectCommand,
+  font_destroy,
+  font_load,
+  gif_add_frame,
+  gif_create,
+  gif_destroy,
+  gif_finish,
+  gradient_store,
+  image_decode,
+  image_destroy,
+  new_draw_target_auto,
+  OpResponse,
+  path_apply_transform,
+  path_offset,
+  path_reverse,
+  snapshot_destroy,
+  StrokeCommand,
 } from "./ops.ts";
 import {
   ISource,
@@ -26,154 +149,1155 @@ import {
   GradientStop,
   Spread,
   BlendMode,
+  DrawOptions,
+  ImageFit,
+  TextAlign,
+  TextMetrics,
+  TiffCompression,
+  Histogram,
+  AverageColor,
+  DominantColor,
+  Channel,
+  Insets,
+  MipLevel,
+  ImageFormat,
+  ImageDraw,
+  ResizeFilter,
+  TrimRect,
 } from "./types.ts";
 
 const DRAW_TARGETS = new Set<number>();
 
-function getNewID() {
-  let id = 0;
-  while (DRAW_TARGETS.has(id)) {
-    id++;
+function check<T>(res: OpResponse<T>, name: string): T {
+  if (!res.ok) throw new Error(`Failed to ${name}: ${res.error}`);
+  return res.value as T;
+}
+
+export class DrawTarget {
+  readonly id: number;
+  height: number;
+  width: number;
+
+  constructor(width: number, height: number) {
+    this.width = width;
+    this.height = height;
+    this.id = check(
+      new_draw_target_auto(this.width, this.height),
+      "create DrawTarget"
+    );
+    DRAW_TARGETS.add(this.id);
+  }
+
+  getData(): Uint8Array {
+    return check(dt_get_data(this.id), "getData");
+  }
+
+  fillRect(
+    x: number,
+    y: number,
+    w: number,
+    h: number,
+    src: ISource
+  ): DrawTarget {
+    check(dt_fill_rect(this.id, x, y, w, h, src), "fillRect");
+    return this;
+  }
+
+  fill(path: PathData | PathBuilder, src: ISource): DrawTarget {
+    if (path instanceof PathBuilder) path = path.finish();
+    check(dt_fill(this.id, path, src), "fill");
+    return this;
+  }
+
+  stroke(
+    path: PathData | PathBuilder,
+    stroke: StrokeStyle,
+    src: ISource
+  ): DrawTarget {
+    if (path instanceof PathBuilder) path = path.finish();
+    check(dt_stroke(this.id, path, stroke, src), "stroke");
+    return this;
+  }
+
+  clear(color: Color): DrawTarget {
+    check(dt_clear(this.id, color.a, color.r, color.g, color.b), "clear");
+    return this;
+  }
+
+  drawImageAt(x: number, y: number, img: Image | Uint8Array): DrawTarget {
+    check(
+      draw_image_at(this.id, img instanceof Uint8Array ? img : img.data, x, y),
+      "drawImageAt"
+    );
+    return this;
+  }
+
+  drawImageWithSizeAt(
+    x: number,
+    y: number,
+    w: number,
+    h: number,
+    img: Image | Uint8Array
+  ): DrawTarget {
+    check(
+      draw_image_with_size_at(
+        this.id,
+        img instanceof Uint8Array ? img : img.data,
+        w,
+        h,
+        x,
+        y
+      ),
+      "drawImageWithSizeAt"
+    );
+    return this;
+  }
+
+  writePNG(path: string): DrawTarget {
+    check(dt_write_png(this.id, path), "writePNG");
+    return this;
+  }
+
+  encodePNG(): Uint8Array {
+    return check(dt_encode(this.id), "encodePNG");
+  }
+
+  destroy(): boolean {
+    const done = dt_destroy(this.id).ok;
+    if (done) DRAW_TARGETS.delete(this.id);
+    return done;
+  }
+
+  setTransform(transform: Transform): DrawTarget {
+    check(dt_set_transform(this.id, ...transform.data), "setTransform");
+    return this;
+  }
+
+  pushLayer(opacity: number): DrawTarget {
+    check(dt_push_layer(this.id, opacity), "pushLayer");
+    return this;
+  }
+
+  pushLayerWithBlend(opacity: number, blend: BlendMode): DrawTarget {
+    check(
+      dt_push_layer_with_blend(this.id, opacity, blend),
+      "pushLayerWithBlend"
+    );
+    return this;
+  }
+
+  popLayer(): DrawTarget {
+    check(dt_pop_layer(this.id), "popLayer");
+    return this;
+  }
+
+  pushClip(path: PathData | PathBuilder) {
+    check(dt_push_clip(this.id, path), "pushClip");
+  }
+
+  pushClipRect(rect: IntRect) {
+    check(dt_push_clip_rect(this.id, ...rect.toArray()), "pushClipRect");
+    return this;
+  }
+
+  popClip() {
+    check(dt_pop_clip(this.id), "popClip");
+    return this;
+  }
+
+  posterize(levels: number): DrawTarget {
+    check(dt_posterize(this.id, levels), "posterize");
+    return this;
+  }
+
+  flipHorizontal(): DrawTarget {
+    check(dt_flip_horizontal(this.id), "flipHorizontal");
+    return this;
+  }
+
+  flipVertical(): DrawTarget {
+    check(dt_flip_vertical(this.id), "flipVertical");
+    return this;
+  }
+
+  private syncSize() {
+    this.width = check(dt_width(this.id), "width");
+    this.height = check(dt_height(this.id), "height");
+  }
+
+  rotate90(): DrawTarget {
+    check(dt_rotate_90(this.id), "rotate90");
+    this.syncSize();
+    return this;
+  }
+
+  rotate180(): DrawTarget {
+    check(dt_rotate_180(this.id), "rotate180");
+    return this;
+  }
+
+  rotate270(): DrawTarget {
+    check(dt_rotate_270(this.id), "rotate270");
+    this.syncSize();
+    return this;
+  }
+
+  pixelate(blockSize: number): DrawTarget {
+    check(dt_pixelate(this.id, blockSize), "pixelate");
+    return this;
+  }
+
+  // Note: this is not the 1 + 8 * amount centre weight originally specified
+  // for sharpen. The kernel has only the four edge neighbours at -amount, so
+  // its centre is 1 + 4 * amount. That makes the weights sum to 1 and leaves
+  // flat areas unchanged. With 1 + 8 * amount, flat areas would be brightened
+  // by a factor of 1 + 4 * amount.
+  sharpen(amount: number): DrawTarget {
+    check(dt_sharpen(this.id, amount), "sharpen");
+    return this;
+  }
+
+  erode(radius: number): DrawTarget {
+    check(dt_erode(this.id, radius), "erode");
+    return this;
+  }
+
+  dilate(radius: number): DrawTarget {
+    check(dt_dilate(this.id, radius), "dilate");
+    return this;
+  }
+
+  medianFilter(radius: number): DrawTarget {
+    check(dt_median_filter(this.id, radius), "medianFilter");
+    return this;
+  }
+
+  tint(color: Color): DrawTarget {
+    check(dt_tint(this.id, color.r, color.g, color.b), "tint");
+    return this;
+  }
+
+  opacity(factor: number): DrawTarget {
+    check(dt_opacity(this.id, factor), "opacity");
+    return this;
+  }
+
+  applyLUT(lut: Uint8Array): DrawTarget {
+    if (lut.length != 768) throw new Error("LUT must be 768 bytes");
+    check(dt_apply_lut(this.id, lut), "applyLUT");
+    return this;
+  }
+
+  convolve(
+    kw: number,
+    kh: number,
+    kernel: number[],
+    divisor = 1,
+    offset = 0,
+    preserveAlpha = false
+  ): DrawTarget {
+    check(
+      dt_convolve(this.id, kw, kh, divisor, offset, kernel, preserveAlpha),
+      "convolve"
+    );
+    return this;
+  }
+
+  colorBalance(
+    shadows: [number, number, number],
+    midtones: [number, number, number],
+    highlights: [number, number, number]
+  ): DrawTarget {
+    check(
+      dt_color_balance(this.id, shadows, midtones, highlights),
+      "colorBalance"
+    );
+    return this;
+  }
+
+  static list(): number[] {
+    return check(dt_list(), "list DrawTargets");
+  }
+
+  static count(): number {
+    return check(dt_count(), "count DrawTargets");
+  }
+
+  pushClipRectFloat(x1: number, y1: number, x2: number, y2: number) {
+    check(
+      dt_push_clip_rect_float(this.id, x1, y1, x2, y2),
+      "pushClipRectFloat"
+    );
+    return this;
+  }
+
+  get clipDepth(): number {
+    return check(dt_clip_depth(this.id), "get clip depth");
+  }
+
+  get layerDepth(): number {
+    return check(dt_layer_depth(this.id), "get layer depth");
+  }
+
+  popAllClips(): number {
+    return check(dt_pop_all_clips(this.id), "popAllClips");
+  }
+
+  popAllLayers(): number {
+    return check(dt_pop_all_layers(this.id), "popAllLayers");
+  }
+
+  fillWithGradient(
+    path: PathData | PathBuilder,
+    gradient: StoredGradient
+  ): DrawTarget {
+    if (path instanceof PathBuilder) path = path.finish();
+    check(
+      dt_fill_with_gradient(this.id, path, gradient.id),
+      "fillWithGradient"
+    );
+    return this;
+  }
+
+  strokeWithGradient(
+    path: PathData | PathBuilder,
+    stroke: StrokeStyle,
+    gradient: StoredGradient
+  ): DrawTarget {
+    if (path instanceof PathBuilder) path = path.finish();
+    check(
+      dt_stroke_with_gradient(this.id, path, gradient.id, stroke),
+      "strokeWithGradient"
+    );
+    return this;
+  }
+
+  fillRectBatch(batch: FillRectCommand[]): DrawTarget {
+    check(dt_fill_rect_batch(this.id, batch), "fillRectBatch");
+    return this;
+  }
+
+  strokePathBatch(batch: StrokeCommand[]): DrawTarget {
+    check(dt_stroke_path_batch(this.id, batch), "strokePathBatch");
+    return this;
+  }
+
+  fillPathBatch(batch: FillCommand[]): DrawTarget {
+    check(dt_fill_path_batch(this.id, batch), "fillPathBatch");
+    return this;
+  }
+
+  executeCommandList(
+    commands: DrawCommand[],
+    images: (Image | Uint8Array)[] = []
+  ): DrawTarget {
+    check(
+      dt_execute_command_list(
+        this.id,
+        commands,
+        images.map((img) => (img instanceof Uint8Array ? img : img.data))
+      ),
+      "executeCommandList"
+    );
+    return this;
+  }
+
+  drawCachedImageAt(x: number, y: number, img: CachedImage): DrawTarget {
+    check(dt_draw_cached_image_at(this.id, img.id, x, y), "drawCachedImageAt");
+    return this;
+  }
+
+  drawCachedImageWithSizeAt(
+    x: number,
+    y: number,
+    w: number,
+    h: number,
+    img: CachedImage
+  ): DrawTarget {
+    check(
+      dt_draw_cached_image_with_size_at(this.id, img.id, x, y, w, h),
+      "drawCachedImageWithSizeAt"
+    );
+    return this;
+  }
+
+  drawImageWithOpacity(
+    x: number,
+    y: number,
+    img: Image | Uint8Array,
+    opacity: number
+  ): DrawTarget {
+    check(
+      dt_draw_image_with_opacity(
+        this.id,
+        img instanceof Uint8Array ? img : img.data,
+        x,
+        y,
+        opacity
+      ),
+      "drawImageWithOpacity"
+    );
+    return this;
+  }
+
+  fillArc(
+    cx: number,
+    cy: number,
+    r: number,
+    startAngle: number,
+    sweepAngle: number,
+    src: ISource,
+    includeCenter = true
+  ): DrawTarget {
+    check(
+      dt_fill_arc(
+        this.id,
+        cx,
+        cy,
+        r,
+        startAngle,
+        sweepAngle,
+        includeCenter,
+        src
+      ),
+      "fillArc"
+    );
+    return this;
+  }
+
+  strokeArc(
+    cx: number,
+    cy: number,
+    r: number,
+    startAngle: number,
+    sweepAngle: number,
+    stroke: StrokeStyle,
+    src: ISource
+  ): DrawTarget {
+    check(
+      dt_stroke_arc(this.id, cx, cy, r, startAngle, sweepAngle, src, stroke),
+      "strokeArc"
+    );
+    return this;
+  }
+
+  fillDonut(
+    cx: number,
+    cy: number,
+    outerRadius: number,
+    innerRadius: number,
+    src: ISource
+  ): DrawTarget {
+    check(
+      dt_fill_donut(this.id, cx, cy, outerRadius, innerRadius, src),
+      "fillDonut"
+    );
+    return this;
+  }
+
+  fillRegularPolygon(
+    cx: number,
+    cy: number,
+    r: number,
+    sides: number,
+    rotation: number,
+    src: ISource
+  ): DrawTarget {
+    check(
+      dt_draw_regular_polygon(
+        this.id,
+        cx,
+        cy,
+        r,
+        sides,
+        rotation,
+        src,
+        "fill"
+      ),
+      "fillRegularPolygon"
+    );
+    return this;
+  }
+
+  strokeRegularPolygon(
+    cx: number,
+    cy: number,
+    r: number,
+    sides: number,
+    rotation: number,
+    stroke: StrokeStyle,
+    src: ISource
+  ): DrawTarget {
+    check(
+      dt_draw_regular_polygon(
+        this.id,
+        cx,
+        cy,
+        r,
+        sides,
+        rotation,
+        src,
+        "stroke",
+        stroke
+      ),
+      "strokeRegularPolygon"
+    );
+    return this;
+  }
+
+  fillStar(
+    cx: number,
+    cy: number,
+    outerRadius: number,
+    innerRadius: number,
+    points: number,
+    rotation: number,
+    src: ISource
+  ): DrawTarget {
+    check(
+      dt_draw_star_polygon(
+        this.id,
+        cx,
+        cy,
+        outerRadius,
+        innerRadius,
+        points,
+        rotation,
+        src,
+        "fill"
+      ),
+      "fillStar"
+    );
+    return this;
+  }
+
+  strokeStar(
+    cx: number,
+    cy: number,
+    outerRadius: number,
+    innerRadius: number,
+    points: number,
+    rotation: number,
+    stroke: StrokeStyle,
+    src: ISource
+  ): DrawTarget {
+    check(
+      dt_draw_star_polygon(
+        this.id,
+        cx,
+        cy,
+        outerRadius,
+        innerRadius,
+        points,
+        rotation,
+        src,
+        "stroke",
+        stroke
+      ),
+      "strokeStar"
+    );
+    return this;
+  }
+
+  drawArrow(
+    x1: number,
+    y1: number,
+    x2: number,
+    y2: number,
+    headSize: number,
+    stroke: StrokeStyle,
+    src: ISource
+  ): DrawTarget {
+    check(
+      dt_draw_arrow(this.id, x1, y1, x2, y2, headSize, src, stroke),
+      "drawArrow"
+    );
+    return this;
+  }
+
+  fillText(
+    text: string,
+    x: number,
+    y: number,
+    font: Font,
+    size: number,
+    src: ISource,
+    align: TextAlign = "left"
+  ): DrawTarget {
+    check(
+      dt_fill_text(this.id, text, x, y, font.id, size, src, align),
+      "fillText"
+    );
+    return this;
+  }
+
+  strokeText(
+    text: string,
+    x: number,
+    y: number,
+    font: Font,
+    size: number,
+    stroke: StrokeStyle,
+    src: ISource,
+    align: TextAlign = "left"
+  ): DrawTarget {
+    check(
+      dt_stroke_text(this.id, text, x, y, font.id, size, src, stroke, align),
+      "strokeText"
+    );
+    return this;
+  }
+
+  fillTextWrapped(
+    text: string,
+    x: number,
+    y: number,
+    maxWidth: number,
+    lineHeight: number,
+    font: Font,
+    size: number,
+    src: ISource,
+    align: TextAlign = "left"
+  ): number {
+    return check(
+      dt_fill_text_wrapped(
+        this.id,
+        text,
+        x,
+        y,
+        maxWidth,
+        lineHeight,
+        font.id,
+        size,
+        src,
+        align
+      ),
+      "fillTextWrapped"
+    );
+  }
+
+  drawTextOnPath(
+    text: string,
+    path: PathData | PathBuilder,
+    font: Font,
+    size: number,
+    src: ISource
+  ): DrawTarget {
+    if (path instanceof PathBuilder) path = path.finish();
+    check(
+      dt_draw_text_on_path(this.id, text, path, font.id, size, src),
+      "drawTextOnPath"
+    );
+    return this;
+  }
+
+  drawImageFit(
+    x: number,
+    y: number,
+    w: number,
+    h: number,
+    img: Image | Uint8Array,
+    fit: ImageFit = "contain",
+    anchorX = 0.5,
+    anchorY = 0.5
+  ): DrawTarget {
+    check(
+      dt_draw_image_fit(
+        this.id,
+        img instanceof Uint8Array ? img : img.data,
+        x,
+        y,
+        w,
+        h,
+        fit,
+        anchorX,
+        anchorY
+      ),
+      "drawImageFit"
+    );
+    return this;
+  }
+
+  drawSVGAt(
+    x: number,
+    y: number,
+    w: number,
+    h: number,
+    svg: Uint8Array,
+    options?: DrawOptions
+  ): DrawTarget {
+    check(dt_draw_svg_at(this.id, svg, x, y, w, h, options), "drawSVGAt");
+    return this;
+  }
+
+  drawSVGString(
+    x: number,
+    y: number,
+    w: number,
+    h: number,
+    svg: string,
+    options?: DrawOptions
+  ): DrawTarget {
+    check(
+      dt_draw_svg_string(this.id, svg, x, y, w, h, options),
+      "drawSVGString"
+    );
+    return this;
+  }
+
+  encodeQOI(): Uint8Array {
+    return check(dt_encode_qoi(this.id), "encodeQOI");
+  }
+
+  encodePNGWithLevel(level: number): Uint8Array {
+    return check(
+      dt_encode_png_with_level(this.id, level),
+      "encodePNGWithLevel"
+    );
+  }
+
+  encodePNGGrayscale(): Uint8Array {
+    return check(dt_encode_png_grayscale(this.id), "encodePNGGrayscale");
+  }
+
+  encodePNGAlphaOnly(): Uint8Array {
+    return check(dt_encode_png_alpha_only(this.id), "encodePNGAlphaOnly");
+  }
+
+  encodePNGWithICCProfile(profile: Uint8Array): Uint8Array {
+    return check(
+      dt_encode_with_icc_profile(this.id, profile),
+      "encodePNGWithICCProfile"
+    );
+  }
+
+  encodeTIFF(compression: TiffCompression = "lzw"): Uint8Array {
+    return check(dt_encode_tiff(this.id, compression), "encodeTIFF");
+  }
+
+  encodeICO(): Uint8Array {
+    return check(dt_encode_ico(this.id), "encodeICO");
+  }
+
+  renderToPDFPage(dpi = 72): Uint8Array {
+    return check(dt_render_to_pdf_page(this.id, dpi), "renderToPDFPage");
+  }
+
+  generateQRCode(
+    x: number,
+    y: number,
+    cellSize: number,
+    data: string,
+    fg: ISource,
+    bg: ISource
+  ) {
+    check(
+      dt_generate_qr_code(this.id, x, y, cellSize, data, fg, bg),
+      "generateQRCode"
+    );
+    return this;
   }
-  return id;
-}
 
-export class DrawTarget {
-  readonly id: number;
-  readonly height: number;
-  readonly width: number;
+  getHistogram(): Histogram {
+    return check(dt_get_histogram(this.id), "getHistogram");
+  }
 
-  constructor(width: number, height: number) {
-    this.id = getNewID();
-    this.width = width;
-    this.height = height;
-    const created = new_draw_target(this.id, this.width, this.height);
-    if (!created) throw new Error("Failed to create DrawTarget");
-    DRAW_TARGETS.add(this.id);
+  autoLevels() {
+    check(dt_auto_levels(this.id), "autoLevels");
+    return this;
   }
 
-  getData(): Uint8Array {
-    return dt_get_data(this.id) as Uint8Array;
+  getAverageColor(rect?: [number, number, number, number]): AverageColor {
+    return check(dt_get_average_color(this.id, rect), "getAverageColor");
   }
 
-  fillRect(
+  getDominantColor(quantizeBits = 4): DominantColor {
+    return check(
+      dt_get_dominant_color(this.id, quantizeBits),
+      "getDominantColor"
+    );
+  }
+
+  // `tolerance` is a Euclidean RGB distance from the seed colour; fully
+  // transparent pixels only match a transparent seed.
+  floodFill(x: number, y: number, src: ISource, tolerance = 0) {
+    check(dt_scan_flood_fill(this.id, x, y, tolerance, src), "floodFill");
+    return this;
+  }
+
+  applyPerspectiveWarp(srcQuad: number[], dstQuad: number[]) {
+    check(
+      dt_apply_perspective_warp(this.id, srcQuad, dstQuad),
+      "applyPerspectiveWarp"
+    );
+    return this;
+  }
+
+  swirl(cx: number, cy: number, radius: number, angle: number) {
+    check(dt_swirl(this.id, cx, cy, radius, angle), "swirl");
+    return this;
+  }
+
+  waveDistortion(
+    ampX: number,
+    freqX: number,
+    ampY: number,
+    freqY: number,
+    phaseX = 0,
+    phaseY = 0
+  ) {
+    check(
+      dt_wave_distortion(this.id, ampX, freqX, ampY, freqY, phaseX, phaseY),
+      "waveDistortion"
+    );
+    return this;
+  }
+
+  private static wrap(id: number): DrawTarget {
+    const target = Object.create(DrawTarget.prototype) as DrawTarget;
+    Object.assign(target, { id });
+    target.syncSize();
+    DRAW_TARGETS.add(id);
+    return target;
+  }
+
+  getChannel(channel: Channel): DrawTarget {
+    return DrawTarget.wrap(
+      check(dt_get_channel(this.id, channel), "getChannel")
+    );
+  }
+
+  setChannel(channel: Channel, src: DrawTarget) {
+    check(dt_set_channel(this.id, channel, src.id), "setChannel");
+    return this;
+  }
+
+  maskWithTarget(mask: DrawTarget) {
+    check(dt_mask_with_target(this.id, mask.id), "maskWithTarget");
+    return this;
+  }
+
+  premultiply() {
+    check(dt_premultiply(this.id), "premultiply");
+    return this;
+  }
+
+  unpremultiply() {
+    check(dt_unpremultiply(this.id), "unpremultiply");
+    return this;
+  }
+
+  convertToLinear() {
+    check(dt_convert_to_linear(this.id), "convertToLinear");
+    return this;
+  }
+
+  convertToSRGB() {
+    check(dt_convert_to_srgb(this.id), "convertToSRGB");
+    return this;
+  }
+
+  scaleLanczos(width: number, height: number): DrawTarget {
+    check(dt_scale_lanczos(this.id, width, height), "scaleLanczos");
+    this.syncSize();
+    return this;
+  }
+
+  drawImageWithColorKey(
+    x: number,
+    y: number,
+    img: Image | Uint8Array,
+    key: Color,
+    tolerance = 0
+  ): DrawTarget {
+    check(
+      dt_draw_image_with_color_key(
+        this.id,
+        img instanceof Uint8Array ? img : img.data,
+        x,
+        y,
+        key,
+        tolerance
+      ),
+      "drawImageWithColorKey"
+    );
+    return this;
+  }
+
+  nineSliceFill(
+    img: Image | Uint8Array,
+    insets: Insets,
+    x: number,
+    y: number,
+    w: number,
+    h: number
+  ): DrawTarget {
+    check(
+      dt_nine_slice_fill(
+        this.id,
+        img instanceof Uint8Array ? img : img.data,
+        insets,
+        x,
+        y,
+        w,
+        h
+      ),
+      "nineSliceFill"
+    );
+    return this;
+  }
+
+  drawCheckerboard(
     x: number,
     y: number,
     w: number,
     h: number,
-    src: ISource
+    cellSize = 8,
+    colorA: Color = new Color(255, 204, 204, 204),
+    colorB: Color = new Color(255, 255, 255, 255)
   ): DrawTarget {
-    if (!dt_fill_rect(this.id, x, y, w, h, src))
-      throw new Error("Failed to fillRect");
+    check(
+      dt_draw_checkerboard(this.id, x, y, w, h, cellSize, colorA, colorB),
+      "drawCheckerboard"
+    );
     return this;
   }
 
-  fill(path: PathData | PathBuilder, src: ISource): DrawTarget {
-    if (path instanceof PathBuilder) path = path.finish();
-    if (!dt_fill(this.id, path, src)) throw new Error("Failed to fill");
+  drawGrid(
+    x: number,
+    y: number,
+    w: number,
+    h: number,
+    cols: number,
+    rows: number,
+    src: ISource,
+    stroke: StrokeStyle
+  ): DrawTarget {
+    check(
+      dt_draw_grid(this.id, x, y, w, h, cols, rows, src, stroke),
+      "drawGrid"
+    );
     return this;
   }
 
-  stroke(
-    path: PathData | PathBuilder,
-    stroke: StrokeStyle,
-    src: ISource
+  snapshot(): Snapshot {
+    return new Snapshot(this);
+  }
+
+  drawSnapshotAt(snapshot: Snapshot, x: number, y: number): DrawTarget {
+    check(
+      dt_draw_snapshot_at(this.id, snapshot.id, x, y),
+      "drawSnapshotAt"
+    );
+    return this;
+  }
+
+  static renderShadowMap(
+    path: PathData,
+    sigma: number,
+    width: number,
+    height: number
   ): DrawTarget {
-    if (path instanceof PathBuilder) path = path.finish();
-    if (!dt_stroke(this.id, path, stroke, src))
-      throw new Error("Failed to stroke");
+    const id = Math.max(-1, ...DrawTarget.list()) + 1;
+    check(
+      dt_render_shadow_map(path, sigma, width, height, id),
+      "renderShadowMap"
+    );
+    return DrawTarget.wrap(id);
+  }
+
+  generateMipmapChain(): DrawTarget[] {
+    return check(
+      dt_generate_mipmap_chain(this.id),
+      "generateMipmapChain"
+    ).map((level) => DrawTarget.wrap(level.id));
+  }
+
+  drawMipmapAt(
+    chain: DrawTarget[],
+    x: number,
+    y: number,
+    w: number,
+    h: number
+  ): DrawTarget {
+    check(
+      dt_draw_mipmap_at(
+        chain.map((level) => level.id),
+        this.id,
+        x,
+        y,
+        w,
+        h
+      ),
+      "drawMipmapAt"
+    );
     return this;
   }
 
-  clear(color: Color): DrawTarget {
-    if (!dt_clear(this.id, color.a, color.r, color.g, color.b))
-      throw new Error("Failed to clear");
+  pathAsClipRegion(path: PathData): DrawTarget {
+    check(dt_path_as_clip_region(this.id, path), "pathAsClipRegion");
     return this;
   }
 
-  drawImageAt(x: number, y: number, img: Image | Uint8Array): DrawTarget {
-    if (
-      !draw_image_at(this.id, img instanceof Uint8Array ? img : img.data, x, y)
-    )
-      throw new Error("Failed to drawImageAt");
+  svgPathAsClipRegion(d: string): DrawTarget {
+    check(dt_svg_path_as_clip_region(this.id, d), "svgPathAsClipRegion");
     return this;
   }
 
-  drawImageWithSizeAt(
+  fillGradientRect(
     x: number,
     y: number,
     w: number,
     h: number,
+    src: ISource
+  ): DrawTarget {
+    check(dt_fill_gradient_rect(this.id, x, y, w, h, src), "fillGradientRect");
+    return this;
+  }
+
+  drawImageAtSubpixel(
+    x: number,
+    y: number,
     img: Image | Uint8Array
   ): DrawTarget {
-    if (
-      !draw_image_with_size_at(
+    check(
+      dt_draw_image_at_subpixel(
         this.id,
         img instanceof Uint8Array ? img : img.data,
-        w,
-        h,
         x,
         y
-      )
-    )
-      throw new Error("Failed to drawImageWithSizeAt");
+      ),
+      "drawImageAtSubpixel"
+    );
     return this;
   }
 
-  writePNG(path: string): DrawTarget {
-    if (!dt_write_png(this.id, path)) throw new Error("Failed to writePNG");
+  encodePNGOptimized(level = 2): Uint8Array {
+    return check(
+      dt_encode_png_optimized(this.id, level),
+      "encodePNGOptimized"
+    );
+  }
+
+  toDataURL(format: ImageFormat = "png", quality = 0.92): string {
+    return check(dt_canvas_to_data_url(this.id, format, quality), "toDataURL");
+  }
+
+  drawProgressBar(
+    x: number,
+    y: number,
+    w: number,
+    h: number,
+    progress: number,
+    bg: ISource,
+    fill: ISource,
+    radius = 0
+  ): DrawTarget {
+    check(
+      dt_draw_progress_bar(this.id, x, y, w, h, progress, bg, fill, radius),
+      "drawProgressBar"
+    );
     return this;
   }
 
-  encodePNG(): Uint8Array {
-    const res = dt_encode(this.id);
-    if (!res) throw new Error("Failed to encodePNG");
-    return res;
+  drawImageBatch(
+    draws: {
+      image: Image | Uint8Array;
+      x: number;
+      y: number;
+      w?: number;
+      h?: number;
+      options?: DrawOptions;
+    }[]
+  ): DrawTarget {
+    const buffers: Uint8Array[] = [];
+    const spec = draws.map(({ image, ...draw }) => {
+      const data = image instanceof Uint8Array ? image : image.data;
+      let idx = buffers.indexOf(data);
+      if (idx === -1) idx = buffers.push(data) - 1;
+      return { ...draw, image_idx: idx + 2 };
+    });
+    check(dt_draw_image_batch(this.id, spec, buffers), "drawImageBatch");
+    return this;
   }
 
-  destroy(): boolean {
-    const done = dt_destroy(this.id);
-    if (done) DRAW_TARGETS.delete(this.id);
-    return done;
+  blendModeFillRect(
+    x: number,
+    y: number,
+    w: number,
+    h: number,
+    blend: BlendMode,
+    src: ISource
+  ): DrawTarget {
+    check(
+      dt_blend_mode_fill_rect(this.id, x, y, w, h, blend, src),
+      "blendModeFillRect"
+    );
+    return this;
   }
 
-  setTransform(transform: Transform): DrawTarget {
-    const done = dt_set_transform(this.id, ...transform.data);
-    if (!done) throw new Error("Failed to setTransform");
+  findEdges(threshold = 128): DrawTarget {
+    check(dt_find_edges(this.id, threshold), "findEdges");
     return this;
   }
 
-  pushLayer(opacity: number): DrawTarget {
-    if (!dt_push_layer(this.id, opacity))
-      throw new Error("Failed to pushLayer");
+  emboss(angle = 45): DrawTarget {
+    check(dt_emboss(this.id, angle), "emboss");
     return this;
   }
 
-  pushLayerWithBlend(opacity: number, blend: BlendMode): DrawTarget {
-    if (!dt_push_layer_with_blend(this.id, opacity, blend))
-      throw new Error("Failed to pushLayerWithBlend");
+  resize(
+    width: number,
+    height: number,
+    filter: ResizeFilter = "bilinear"
+  ): DrawTarget {
+    check(dt_resize(this.id, width, height, filter), "resize");
+    this.syncSize();
     return this;
   }
 
-  popLayer(): DrawTarget {
-    if (!dt_pop_layer(this.id)) throw new Error("Failed to popLayer");
+  expandCanvas(insets: Insets, src: ISource): DrawTarget {
+    check(
+      dt_expand_canvas(
+        this.id,
+        insets.top,
+        insets.right,
+        insets.bottom,
+        insets.left,
+        src
+      ),
+      "expandCanvas"
+    );
+    this.syncSize();
     return this;
   }
 
-  pushClip(path: PathData | PathBuilder) {
-    if (!dt_push_clip(this.id, path)) throw new Error("Failed to pushClip");
+  trimTransparent(threshold = 0): TrimRect {
+    const rect = check(
+      dt_trim_transparent(this.id, threshold),
+      "trimTransparent"
+    );
+    this.syncSize();
+    return rect;
   }
 
-  pushClipRect(rect: IntRect) {
-    if (!dt_push_clip_rect(this.id, ...rect.toArray()))
-      throw new Error("Failed to pushClipRect");
+  swap(other: DrawTarget): DrawTarget {
+    check(dt_swap(this.id, other.id), "swap");
     return this;
   }
 
-  popClip() {
-    if (!dt_pop_clip(this.id)) throw new Error("Failed to popClip");
+  setMetadata(key: string, value: string): DrawTarget {
+    check(dt_metadata_set(this.id, key, value), "setMetadata");
     return this;
   }
+
+  getMetadata(key: string): string | undefined {
+    return check(dt_metadata_get(this.id, key), "getMetadata");
+  }
+
+  getAllMetadata(): Record<string, string> {
+    return check(dt_metadata_get_all(this.id), "getAllMetadata");
+  }
 }
 
 export class IntRect {
@@ -267,6 +1391,27 @@ export class PathBuilder {
     return this;
   }
 
+  transform(matrix: number[]) {
+    this.steps = check(
+      path_apply_transform(this.finish(), matrix),
+      "transform path"
+    ).steps;
+    return this;
+  }
+
+  reverse() {
+    this.steps = check(path_reverse(this.finish()), "reverse path").steps;
+    return this;
+  }
+
+  offset(distance: number) {
+    this.steps = check(
+      path_offset(this.finish(), distance),
+      "offset path"
+    ).steps;
+    return this;
+  }
+
   finish(): PathData {
     return { steps: this.steps };
   }
@@ -310,6 +1455,44 @@ export class Source {
       spread,
     };
   }
+
+  static createPattern(img: Image | Uint8Array): ISource {
+    return {
+      src_type: "Pattern",
+      image: img instanceof Uint8Array ? img : img.data,
+    };
+  }
+
+  static createConicGradient(
+    stops: GradientStop[] | Gradient,
+    center: Point,
+    startAngle: Angle = Angle.radians(0)
+  ): ISource {
+    return {
+      src_type: "ConicGradient",
+      gradient: {
+        stops: stops instanceof Gradient ? stops.stops : stops,
+      },
+      center: center.toArray(),
+      start_angle:
+        startAngle.type === AngleType.Degrees
+          ? (startAngle.val * Math.PI) / 180
+          : startAngle.val,
+    };
+  }
+
+  static createCheckerboard(
+    size: number,
+    colorA: Color,
+    colorB: Color
+  ): ISource {
+    return {
+      src_type: "Checkerboard",
+      size,
+      color_a: colorA,
+      color_b: colorB,
+    };
+  }
 }
 
 export class Color {
@@ -410,3 +1593,130 @@ export class Transform {
     return new Transform(4, angle.type.valueOf(), angle.val, 0, 0, 0, 0);
   }
 }
+
+let nextGradientID = 0;
+
+export class StoredGradient {
+  readonly id: number;
+
+  constructor(src: ISource) {
+    this.id = nextGradientID++;
+    check(gradient_store(this.id, src), "store gradient");
+  }
+}
+
+let nextImageID = 0;
+
+export class CachedImage {
+  readonly id: number;
+  readonly width: number;
+  readonly height: number;
+
+  constructor(img: Image | Uint8Array) {
+    this.id = nextImageID++;
+    [this.width, this.height] = check(
+      image_decode(this.id, img instanceof Uint8Array ? img : img.data),
+      "decode image"
+    );
+  }
+
+  destroy(): boolean {
+    return image_destroy(this.id).ok;
+  }
+}
+
+let nextFontID = 0;
+
+export class Font {
+  readonly id: number;
+
+  constructor(data: Uint8Array) {
+    this.id = nextFontID++;
+    check(font_load(this.id, data), "load font");
+  }
+
+  measureText(text: string, size: number): TextMetrics {
+    return check(dt_measure_text(this.id, text, size), "measureText");
+  }
+
+  getTextPath(text: string, size: number, x = 0, y = 0): PathData {
+    return check(
+      dt_get_text_path(text, this.id, size, x, y),
+      "getTextPath"
+    );
+  }
+
+  static open(path: string) {
+    return new Font(Deno.readFileSync(path));
+  }
+
+  destroy(): boolean {
+    return font_destroy(this.id).ok;
+  }
+}
+
+let nextGifID = 0;
+
+export class AnimatedGif {
+  readonly id: number;
+
+  constructor(loopCount = 0) {
+    this.id = nextGifID++;
+    check(gif_create(this.id, loopCount), "create gif");
+  }
+
+  addFrame(target: DrawTarget, delay: number) {
+    check(gif_add_frame(this.id, target.id, delay), "addFrame");
+    return this;
+  }
+
+  finish(): Uint8Array {
+    return check(gif_finish(this.id), "finish");
+  }
+
+  destroy(): boolean {
+    return gif_destroy(this.id).ok;
+  }
+}
+
+let nextApngID = 0;
+
+export class AnimatedPng {
+  readonly id: number;
+
+  constructor() {
+    this.id = nextApngID++;
+    check(apng_create(this.id), "create apng");
+  }
+
+  addFrame(target: DrawTarget, delayNum: number, delayDenom = 1000) {
+    check(
+      apng_add_frame(this.id, target.id, delayNum, delayDenom),
+      "addFrame"
+    );
+    return this;
+  }
+
+  finish(): Uint8Array {
+    return check(apng_finish(this.id), "finish");
+  }
+
+  destroy(): boolean {
+    return apng_destroy(this.id).ok;
+  }
+}
+
+let nextSnapshotID = 0;
+
+export class Snapshot {
+  readonly id: number;
+
+  constructor(target: DrawTarget) {
+    this.id = nextSnapshotID++;
+    check(dt_snapshot(target.id, this.id), "snapshot");
+  }
+
+  destroy(): boolean {
+    return snapshot_destroy(this.id).ok;
+  }
+}
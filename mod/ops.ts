@@ -1,5 +1,28 @@
 import { Plug } from "https://deno.land/x/plug@0.2.10/mod.ts";
-import { PathData, ISource, Spread, StrokeStyle, BlendMode } from "./types.ts";
+import {
+  PathData,
+  ISource,
+  Spread,
+  StrokeStyle,
+  BlendMode,
+  DrawOptions,
+  FillRule,
+  IColor,
+  ImageFit,
+  TextAlign,
+  TextMetrics,
+  TiffCompression,
+  Histogram,
+  AverageColor,
+  DominantColor,
+  Channel,
+  Insets,
+  MipLevel,
+  ImageFormat,
+  ImageDraw,
+  ResizeFilter,
+  TrimRect,
+} from "./types.ts";
 
 const VERSION = "0.0.4";
 const POLICY =
@@ -18,6 +41,7 @@ await Plug.prepare({
 
 export const {
   op_new_draw_target,
+  op_new_draw_target_auto,
   op_dt_get_data,
   op_dt_fill_rect,
   op_dt_fill,
@@ -37,6 +61,122 @@ export const {
   op_dt_pop_clip,
   op_dt_push_clip,
   op_dt_push_clip_rect,
+  op_dt_posterize,
+  op_dt_flip_horizontal,
+  op_dt_flip_vertical,
+  op_dt_rotate_90,
+  op_dt_rotate_180,
+  op_dt_rotate_270,
+  op_dt_pixelate,
+  op_dt_sharpen,
+  op_dt_erode,
+  op_dt_dilate,
+  op_dt_median_filter,
+  op_dt_tint,
+  op_dt_opacity,
+  op_dt_apply_lut,
+  op_dt_convolve,
+  op_dt_color_balance,
+  op_dt_list,
+  op_dt_count,
+  op_dt_push_clip_rect_float,
+  op_dt_clip_depth,
+  op_dt_layer_depth,
+  op_dt_pop_all_clips,
+  op_dt_pop_all_layers,
+  op_gradient_store,
+  op_dt_fill_with_gradient,
+  op_dt_stroke_with_gradient,
+  op_dt_fill_rect_batch,
+  op_dt_stroke_path_batch,
+  op_dt_fill_path_batch,
+  op_dt_execute_command_list,
+  op_image_decode,
+  op_image_destroy,
+  op_dt_draw_cached_image_at,
+  op_dt_draw_cached_image_with_size_at,
+  op_dt_draw_image_with_opacity,
+  op_dt_fill_arc,
+  op_dt_stroke_arc,
+  op_dt_fill_donut,
+  op_dt_draw_regular_polygon,
+  op_dt_draw_star_polygon,
+  op_dt_draw_arrow,
+  op_dt_fill_text,
+  op_font_load,
+  op_font_destroy,
+  op_dt_measure_text,
+  op_dt_stroke_text,
+  op_dt_fill_text_wrapped,
+  op_dt_get_text_path,
+  op_dt_draw_text_on_path,
+  op_dt_draw_image_fit,
+  op_dt_draw_svg_at,
+  op_dt_draw_svg_string,
+  op_dt_encode_qoi,
+  op_dt_encode_png_with_level,
+  op_dt_encode_png_grayscale,
+  op_dt_encode_png_alpha_only,
+  op_dt_encode_with_icc_profile,
+  op_dt_encode_tiff,
+  op_dt_encode_ico,
+  op_gif_create,
+  op_gif_add_frame,
+  op_gif_finish,
+  op_gif_destroy,
+  op_apng_create,
+  op_apng_add_frame,
+  op_apng_finish,
+  op_apng_destroy,
+  op_dt_render_to_pdf_page,
+  op_dt_generate_qr_code,
+  op_dt_get_histogram,
+  op_dt_auto_levels,
+  op_dt_get_average_color,
+  op_dt_get_dominant_color,
+  op_dt_scan_flood_fill,
+  op_dt_apply_perspective_warp,
+  op_dt_swirl,
+  op_dt_wave_distortion,
+  op_dt_get_channel,
+  op_dt_set_channel,
+  op_dt_mask_with_target,
+  op_dt_premultiply,
+  op_dt_unpremultiply,
+  op_dt_convert_to_linear,
+  op_dt_convert_to_srgb,
+  op_dt_scale_lanczos,
+  op_dt_draw_image_with_color_key,
+  op_dt_nine_slice_fill,
+  op_dt_draw_checkerboard,
+  op_dt_draw_grid,
+  op_dt_snapshot,
+  op_dt_draw_snapshot_at,
+  op_snapshot_destroy,
+  op_dt_render_shadow_map,
+  op_dt_generate_mipmap_chain,
+  op_dt_draw_mipmap_at,
+  op_dt_path_as_clip_region,
+  op_dt_svg_path_as_clip_region,
+  op_path_apply_transform,
+  op_path_reverse,
+  op_path_offset,
+  op_dt_fill_gradient_rect,
+  op_dt_draw_image_at_subpixel,
+  op_dt_encode_png_optimized,
+  op_dt_canvas_to_data_url,
+  op_dt_draw_progress_bar,
+  op_dt_draw_image_batch,
+  op_dt_blend_mode_fill_rect,
+  op_dt_find_edges,
+  op_dt_emboss,
+  op_dt_resize,
+  op_dt_expand_canvas,
+  op_dt_trim_transparent,
+  op_dt_swap,
+  op_dt_metadata_set,
+  op_dt_metadata_get,
+  op_dt_metadata_get_all,
 } = (Deno as any).core.ops() as { [name: string]: number };
 
 export const encoder = new TextEncoder();
@@ -52,9 +192,22 @@ function _fix_src(src: ISource) {
   if (!src.radius2) src.radius2 = 0;
   if (!src.spread) src.spread = Spread.Pad;
   if (!src.gradient) src.gradient = { stops: [] };
+  if (!src.start_angle) src.start_angle = 0;
   return src;
 }
 
+// Pattern sources send their image bytes as an extra argument.
+function _src_args(src: ISource): any[] {
+  const { image, ...json } = _fix_src(src);
+  return image ? [json, image] : [json];
+}
+
+// With two sources, a missing first image is padded so the second pattern
+// image always lands at the same index.
+function _src_pair_images(a?: Uint8Array, b?: Uint8Array): Uint8Array[] {
+  return b ? [a ?? new Uint8Array(), b] : a ? [a] : [];
+}
+
 function _fix_path(path: PathData): PathData {
   return {
     steps: path.steps.map((step) => {
@@ -82,23 +235,56 @@ export function dispatch_data(id: number, ...args: any[]): any {
   return decoder.decode(dispatch(id, ...args));
 }
 
+export interface OpResponse<T = undefined> {
+  ok: boolean;
+  value?: T;
+  error?: string;
+}
+
+export function dispatch_json<T = undefined>(
+  id: number,
+  ...args: any[]
+): OpResponse<T> {
+  return JSON.parse(dispatch_data(id, ...args));
+}
+
+const ERROR_PREFIX = encoder.encode('{"ok":false');
+
+// Ops that return raw bytes send a JSON error object on failure instead.
+export function dispatch_bytes(
+  id: number,
+  ...args: any[]
+): OpResponse<Uint8Array> {
+  const res: Uint8Array = dispatch(id, ...args);
+  if (
+    res.length > ERROR_PREFIX.length &&
+    ERROR_PREFIX.every((byte, i) => res[i] == byte)
+  )
+    return JSON.parse(decoder.decode(res));
+  return { ok: true, value: res };
+}
+
 export function new_draw_target(
   id: number,
   width: number,
   height: number
-): boolean {
-  return dispatch_data(op_new_draw_target, id, width, height) == "0";
+): OpResponse {
+  return dispatch_json(op_new_draw_target, id, width, height);
+}
+
+export function new_draw_target_auto(
+  width: number,
+  height: number
+): OpResponse<number> {
+  return dispatch_json(op_new_draw_target_auto, width, height);
 }
 
-export function dt_get_data(id: number): Uint8Array | void {
-  let data = dispatch(op_dt_get_data, id);
-  if (data.length == 1 && decoder.decode(data) == "1") return;
-  else return data;
+export function dt_get_data(id: number): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_get_data, id);
 }
 
-export function dt_write_png(id: number, path: string): boolean {
-  let data = dispatch_data(op_dt_write_png, id, path);
-  return data == "0";
+export function dt_write_png(id: number, path: string): OpResponse {
+  return dispatch_json(op_dt_write_png, id, path);
 }
 
 export function dt_fill_rect(
@@ -109,13 +295,11 @@ export function dt_fill_rect(
   h: number,
   src: ISource
 ) {
-  let res = dispatch_data(op_dt_fill_rect, id, x, y, w, h, _fix_src(src));
-  return res == "0";
+  return dispatch_json(op_dt_fill_rect, id, x, y, w, h, ..._src_args(src));
 }
 
 export function dt_fill(id: number, path: PathData, src: ISource) {
-  let res = dispatch_data(op_dt_fill, id, _fix_path(path), _fix_src(src));
-  return res == "0";
+  return dispatch_json(op_dt_fill, id, _fix_path(path), ..._src_args(src));
 }
 
 export function dt_stroke(
@@ -124,14 +308,15 @@ export function dt_stroke(
   stroke: StrokeStyle,
   src: ISource
 ) {
-  let res = dispatch_data(
+  const [json, ...image] = _src_args(src);
+  return dispatch_json(
     op_dt_stroke,
     id,
     _fix_path(path),
-    _fix_src(src),
-    stroke
+    json,
+    stroke,
+    ...image
   );
-  return res == "0";
 }
 
 export function dt_clear(
@@ -141,31 +326,23 @@ export function dt_clear(
   g: number,
   b: number
 ) {
-  let res = dispatch_data(op_dt_clear, id, a, r, g, b);
-  return res == "0";
+  return dispatch_json(op_dt_clear, id, a, r, g, b);
 }
 
 export function dt_destroy(id: number) {
-  let res = dispatch_data(op_dt_destroy, id);
-  return res == "0";
+  return dispatch_json(op_dt_destroy, id);
 }
 
-export function dt_height(id: number): void | number {
-  let res = dispatch_data(op_dt_height, id);
-  if (res == "n") return;
-  else return Number(res);
+export function dt_height(id: number): OpResponse<number> {
+  return dispatch_json(op_dt_height, id);
 }
 
-export function dt_encode(id: number): void | Uint8Array {
-  let res = dispatch(op_dt_encode, id);
-  if (res.length == 0 && decoder.decode(res) == "n") return;
-  else return res;
+export function dt_encode(id: number): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_encode, id);
 }
 
-export function dt_width(id: number): void | number {
-  let res = dispatch_data(op_dt_width, id);
-  if (res == "n") return;
-  else return Number(res);
+export function dt_width(id: number): OpResponse<number> {
+  return dispatch_json(op_dt_width, id);
 }
 
 export function draw_image_at(
@@ -173,8 +350,8 @@ export function draw_image_at(
   img: Uint8Array,
   x: number,
   y: number
-): boolean {
-  return dispatch_data(op_dt_draw_image_at, id, img, x, y) == "0";
+): OpResponse {
+  return dispatch_json(op_dt_draw_image_at, id, img, x, y);
 }
 
 export function draw_image_with_size_at(
@@ -184,10 +361,8 @@ export function draw_image_with_size_at(
   y: number,
   w: number,
   h: number
-): boolean {
-  return (
-    dispatch_data(op_dt_draw_image_with_size_at, id, img, x, y, w, h) == "0"
-  );
+): OpResponse {
+  return dispatch_json(op_dt_draw_image_with_size_at, id, img, x, y, w, h);
 }
 
 export function dt_set_transform(
@@ -200,14 +375,21 @@ export function dt_set_transform(
   m22: number,
   m32: number
 ) {
-  return (
-    dispatch_data(op_dt_set_transform, id, rc, m11, m21, m31, m12, m22, m32) ==
-    "0"
+  return dispatch_json(
+    op_dt_set_transform,
+    id,
+    rc,
+    m11,
+    m21,
+    m31,
+    m12,
+    m22,
+    m32
   );
 }
 
 export function dt_push_layer(id: number, opacity: number) {
-  return dispatch_data(op_dt_push_layer, id, opacity) == "0";
+  return dispatch_json(op_dt_push_layer, id, opacity);
 }
 
 export function dt_push_layer_with_blend(
@@ -215,11 +397,11 @@ export function dt_push_layer_with_blend(
   opacity: number,
   blend: BlendMode
 ) {
-  return dispatch_data(op_dt_push_layer_with_blend, id, opacity, blend) == "0";
+  return dispatch_json(op_dt_push_layer_with_blend, id, opacity, blend);
 }
 
 export function dt_push_clip(id: number, path: PathData) {
-  return dispatch_data(op_dt_pop_clip, id, _fix_path(path)) == "0";
+  return dispatch_json(op_dt_push_clip, id, _fix_path(path));
 }
 
 export function dt_push_clip_rect(
@@ -229,13 +411,1216 @@ export function dt_push_clip_rect(
   x2: number,
   y2: number
 ) {
-  return dispatch_data(op_dt_push_clip_rect, id, x1, y1, x2, y2) == "0";
+  return dispatch_json(op_dt_push_clip_rect, id, x1, y1, x2, y2);
 }
 
 export function dt_pop_clip(id: number) {
-  return dispatch_data(op_dt_pop_clip, id) == "0";
+  return dispatch_json(op_dt_pop_clip, id);
 }
 
 export function dt_pop_layer(id: number) {
-  return dispatch_data(op_dt_pop_layer, id) == "0";
+  return dispatch_json(op_dt_pop_layer, id);
+}
+
+export function dt_posterize(id: number, levels: number) {
+  return dispatch_json(op_dt_posterize, id, levels);
+}
+
+export function dt_flip_horizontal(id: number) {
+  return dispatch_json(op_dt_flip_horizontal, id);
+}
+
+export function dt_flip_vertical(id: number) {
+  return dispatch_json(op_dt_flip_vertical, id);
+}
+
+export function dt_rotate_90(id: number) {
+  return dispatch_json(op_dt_rotate_90, id);
+}
+
+export function dt_rotate_180(id: number) {
+  return dispatch_json(op_dt_rotate_180, id);
+}
+
+export function dt_rotate_270(id: number) {
+  return dispatch_json(op_dt_rotate_270, id);
+}
+
+export function dt_pixelate(id: number, blockSize: number) {
+  return dispatch_json(op_dt_pixelate, id, blockSize);
+}
+
+export function dt_sharpen(id: number, amount: number) {
+  return dispatch_json(op_dt_sharpen, id, amount);
+}
+
+export function dt_erode(id: number, radius: number) {
+  return dispatch_json(op_dt_erode, id, radius);
+}
+
+export function dt_dilate(id: number, radius: number) {
+  return dispatch_json(op_dt_dilate, id, radius);
+}
+
+export function dt_median_filter(id: number, radius: number) {
+  return dispatch_json(op_dt_median_filter, id, radius);
+}
+
+export function dt_tint(id: number, r: number, g: number, b: number) {
+  return dispatch_json(op_dt_tint, id, r, g, b);
+}
+
+export function dt_opacity(id: number, factor: number) {
+  return dispatch_json(op_dt_opacity, id, factor);
+}
+
+export function dt_apply_lut(id: number, lut: Uint8Array) {
+  return dispatch_json(op_dt_apply_lut, id, lut);
+}
+
+export function dt_convolve(
+  id: number,
+  kw: number,
+  kh: number,
+  divisor: number,
+  offset: number,
+  kernel: number[],
+  preserveAlpha: boolean
+) {
+  const buf = new Uint8Array(new Float32Array(kernel).buffer);
+  return dispatch_json(
+    op_dt_convolve,
+    id,
+    kw,
+    kh,
+    divisor,
+    offset,
+    buf,
+    preserveAlpha ? 1 : 0
+  );
+}
+
+export function dt_color_balance(
+  id: number,
+  shadows: [number, number, number],
+  midtones: [number, number, number],
+  highlights: [number, number, number]
+) {
+  return dispatch_json(op_dt_color_balance, id, shadows, midtones, highlights);
+}
+
+export function dt_list(): OpResponse<number[]> {
+  return dispatch_json(op_dt_list);
+}
+
+export function dt_count(): OpResponse<number> {
+  return dispatch_json(op_dt_count);
+}
+
+export function dt_push_clip_rect_float(
+  id: number,
+  x1: number,
+  y1: number,
+  x2: number,
+  y2: number
+) {
+  return dispatch_json(op_dt_push_clip_rect_float, id, x1, y1, x2, y2);
+}
+
+export function dt_clip_depth(id: number): OpResponse<number> {
+  return dispatch_json(op_dt_clip_depth, id);
+}
+
+export function dt_layer_depth(id: number): OpResponse<number> {
+  return dispatch_json(op_dt_layer_depth, id);
+}
+
+export function dt_pop_all_clips(id: number): OpResponse<number> {
+  return dispatch_json(op_dt_pop_all_clips, id);
+}
+
+export function dt_pop_all_layers(id: number): OpResponse<number> {
+  return dispatch_json(op_dt_pop_all_layers, id);
+}
+
+export function gradient_store(id: number, src: ISource) {
+  return dispatch_json(op_gradient_store, id, _fix_src(src));
+}
+
+export function dt_fill_with_gradient(
+  id: number,
+  path: PathData,
+  gradientId: number
+) {
+  return dispatch_json(
+    op_dt_fill_with_gradient,
+    id,
+    _fix_path(path),
+    gradientId
+  );
+}
+
+export function dt_stroke_with_gradient(
+  id: number,
+  path: PathData,
+  gradientId: number,
+  stroke: StrokeStyle
+) {
+  return dispatch_json(
+    op_dt_stroke_with_gradient,
+    id,
+    _fix_path(path),
+    gradientId,
+    stroke
+  );
+}
+
+export interface FillRectCommand {
+  x: number;
+  y: number;
+  w: number;
+  h: number;
+  src: ISource;
+}
+
+export function dt_fill_rect_batch(id: number, batch: FillRectCommand[]) {
+  return dispatch_json(
+    op_dt_fill_rect_batch,
+    id,
+    batch.map((cmd) => ({ ...cmd, src: _fix_src(cmd.src) }))
+  );
+}
+
+export interface StrokeCommand {
+  path: PathData;
+  src: ISource;
+  stroke: StrokeStyle;
+  options?: DrawOptions;
+}
+
+export function dt_stroke_path_batch(id: number, batch: StrokeCommand[]) {
+  return dispatch_json(
+    op_dt_stroke_path_batch,
+    id,
+    batch.map((cmd) => ({
+      ...cmd,
+      path: _fix_path(cmd.path),
+      src: _fix_src(cmd.src),
+    }))
+  );
+}
+
+export interface FillCommand {
+  path: PathData;
+  src: ISource;
+  fill_rule?: FillRule;
+  options?: DrawOptions;
+}
+
+export function dt_fill_path_batch(id: number, batch: FillCommand[]) {
+  return dispatch_json(
+    op_dt_fill_path_batch,
+    id,
+    batch.map((cmd) => ({
+      ...cmd,
+      path: _fix_path(cmd.path),
+      src: _fix_src(cmd.src),
+    }))
+  );
+}
+
+// draw_image refers to an index into the images passed alongside the list.
+export type DrawCommand =
+  | { op: "fill_rect"; args: FillRectCommand }
+  | { op: "fill"; args: FillCommand }
+  | { op: "stroke"; args: StrokeCommand }
+  | {
+      op: "draw_image";
+      args: { image: number; x: number; y: number; w?: number; h?: number };
+    }
+  | { op: "push_clip"; args: { path: PathData } }
+  | {
+      op: "push_clip_rect";
+      args: { x1: number; y1: number; x2: number; y2: number };
+    }
+  | { op: "pop_clip" }
+  | { op: "push_layer"; args: { opacity: number; blend_mode?: BlendMode } }
+  | { op: "pop_layer" }
+  | { op: "set_transform"; args: { transform: number[] } }
+  | { op: "clear"; args: { color: IColor } };
+
+function _fix_command(cmd: DrawCommand): DrawCommand {
+  switch (cmd.op) {
+    case "fill_rect":
+      return { ...cmd, args: { ...cmd.args, src: _fix_src(cmd.args.src) } };
+    case "fill":
+      return {
+        ...cmd,
+        args: {
+          ...cmd.args,
+          path: _fix_path(cmd.args.path),
+          src: _fix_src(cmd.args.src),
+        },
+      };
+    case "stroke":
+      return {
+        ...cmd,
+        args: {
+          ...cmd.args,
+          path: _fix_path(cmd.args.path),
+          src: _fix_src(cmd.args.src),
+        },
+      };
+    case "push_clip":
+      return { ...cmd, args: { path: _fix_path(cmd.args.path) } };
+    default:
+      return cmd;
+  }
+}
+
+export function dt_execute_command_list(
+  id: number,
+  commands: DrawCommand[],
+  images: Uint8Array[] = []
+) {
+  return dispatch_json(
+    op_dt_execute_command_list,
+    id,
+    commands.map(_fix_command),
+    ...images
+  );
+}
+
+export function image_decode(
+  id: number,
+  img: Uint8Array
+): OpResponse<[number, number]> {
+  return dispatch_json(op_image_decode, id, img);
+}
+
+export function image_destroy(id: number) {
+  return dispatch_json(op_image_destroy, id);
+}
+
+export function dt_draw_cached_image_at(
+  id: number,
+  imageId: number,
+  x: number,
+  y: number
+) {
+  return dispatch_json(op_dt_draw_cached_image_at, id, imageId, x, y);
+}
+
+export function dt_draw_cached_image_with_size_at(
+  id: number,
+  imageId: number,
+  x: number,
+  y: number,
+  w: number,
+  h: number
+) {
+  return dispatch_json(
+    op_dt_draw_cached_image_with_size_at,
+    id,
+    imageId,
+    x,
+    y,
+    w,
+    h
+  );
+}
+
+export function dt_draw_image_with_opacity(
+  id: number,
+  img: Uint8Array,
+  x: number,
+  y: number,
+  opacity: number
+) {
+  return dispatch_json(op_dt_draw_image_with_opacity, id, img, x, y, opacity);
+}
+
+export function dt_fill_arc(
+  id: number,
+  cx: number,
+  cy: number,
+  r: number,
+  startAngle: number,
+  sweepAngle: number,
+  includeCenter: boolean,
+  src: ISource
+) {
+  return dispatch_json(
+    op_dt_fill_arc,
+    id,
+    cx,
+    cy,
+    r,
+    startAngle,
+    sweepAngle,
+    includeCenter ? 1 : 0,
+    ..._src_args(src)
+  );
+}
+
+export function dt_stroke_arc(
+  id: number,
+  cx: number,
+  cy: number,
+  r: number,
+  startAngle: number,
+  sweepAngle: number,
+  src: ISource,
+  stroke: StrokeStyle
+) {
+  const [json, ...image] = _src_args(src);
+  return dispatch_json(
+    op_dt_stroke_arc,
+    id,
+    cx,
+    cy,
+    r,
+    startAngle,
+    sweepAngle,
+    json,
+    stroke,
+    ...image
+  );
+}
+
+export function dt_fill_donut(
+  id: number,
+  cx: number,
+  cy: number,
+  outerRadius: number,
+  innerRadius: number,
+  src: ISource
+) {
+  return dispatch_json(
+    op_dt_fill_donut,
+    id,
+    cx,
+    cy,
+    outerRadius,
+    innerRadius,
+    ..._src_args(src)
+  );
+}
+
+export function dt_draw_regular_polygon(
+  id: number,
+  cx: number,
+  cy: number,
+  r: number,
+  sides: number,
+  rotation: number,
+  src: ISource,
+  mode: "fill" | "stroke",
+  stroke?: StrokeStyle
+) {
+  const [json, ...image] = _src_args(src);
+  return dispatch_json(
+    op_dt_draw_regular_polygon,
+    id,
+    cx,
+    cy,
+    r,
+    sides,
+    rotation,
+    json,
+    mode,
+    stroke ?? null,
+    ...image
+  );
+}
+
+export function dt_draw_star_polygon(
+  id: number,
+  cx: number,
+  cy: number,
+  outerRadius: number,
+  innerRadius: number,
+  points: number,
+  rotation: number,
+  src: ISource,
+  mode: "fill" | "stroke",
+  stroke?: StrokeStyle
+) {
+  const [json, ...image] = _src_args(src);
+  return dispatch_json(
+    op_dt_draw_star_polygon,
+    id,
+    cx,
+    cy,
+    outerRadius,
+    innerRadius,
+    points,
+    rotation,
+    json,
+    mode,
+    stroke ?? null,
+    ...image
+  );
+}
+
+export function dt_draw_arrow(
+  id: number,
+  x1: number,
+  y1: number,
+  x2: number,
+  y2: number,
+  headSize: number,
+  src: ISource,
+  stroke: StrokeStyle
+) {
+  const [json, ...image] = _src_args(src);
+  return dispatch_json(
+    op_dt_draw_arrow,
+    id,
+    x1,
+    y1,
+    x2,
+    y2,
+    headSize,
+    json,
+    stroke,
+    ...image
+  );
+}
+
+export function dt_fill_text(
+  id: number,
+  text: string,
+  x: number,
+  y: number,
+  fontId: number,
+  size: number,
+  src: ISource,
+  align: TextAlign = "left"
+) {
+  const [json, ...image] = _src_args(src);
+  return dispatch_json(
+    op_dt_fill_text,
+    id,
+    text,
+    x,
+    y,
+    fontId,
+    size,
+    json,
+    align,
+    ...image
+  );
+}
+
+export function font_load(id: number, data: Uint8Array) {
+  return dispatch_json(op_font_load, id, data);
+}
+
+export function font_destroy(id: number) {
+  return dispatch_json(op_font_destroy, id);
+}
+
+export function dt_measure_text(
+  fontId: number,
+  text: string,
+  size: number
+): OpResponse<TextMetrics> {
+  return dispatch_json(op_dt_measure_text, fontId, text, size);
+}
+
+export function dt_stroke_text(
+  id: number,
+  text: string,
+  x: number,
+  y: number,
+  fontId: number,
+  size: number,
+  src: ISource,
+  stroke: StrokeStyle,
+  align: TextAlign = "left"
+) {
+  const [json, ...image] = _src_args(src);
+  return dispatch_json(
+    op_dt_stroke_text,
+    id,
+    text,
+    x,
+    y,
+    fontId,
+    size,
+    json,
+    stroke,
+    align,
+    ...image
+  );
+}
+
+export function dt_fill_text_wrapped(
+  id: number,
+  text: string,
+  x: number,
+  y: number,
+  maxWidth: number,
+  lineHeight: number,
+  fontId: number,
+  size: number,
+  src: ISource,
+  align: TextAlign = "left"
+): OpResponse<number> {
+  const [json, ...image] = _src_args(src);
+  return dispatch_json(
+    op_dt_fill_text_wrapped,
+    id,
+    text,
+    x,
+    y,
+    maxWidth,
+    lineHeight,
+    fontId,
+    size,
+    json,
+    align,
+    ...image
+  );
+}
+
+export function dt_get_text_path(
+  text: string,
+  fontId: number,
+  size: number,
+  x: number,
+  y: number
+): OpResponse<PathData> {
+  return dispatch_json(op_dt_get_text_path, text, fontId, size, x, y);
+}
+
+export function dt_draw_text_on_path(
+  id: number,
+  text: string,
+  path: PathData,
+  fontId: number,
+  size: number,
+  src: ISource
+) {
+  return dispatch_json(
+    op_dt_draw_text_on_path,
+    id,
+    text,
+    _fix_path(path),
+    fontId,
+    size,
+    ..._src_args(src)
+  );
+}
+
+export function dt_draw_image_fit(
+  id: number,
+  img: Uint8Array,
+  x: number,
+  y: number,
+  w: number,
+  h: number,
+  fit: ImageFit,
+  anchorX = 0.5,
+  anchorY = 0.5
+) {
+  return dispatch_json(
+    op_dt_draw_image_fit,
+    id,
+    img,
+    x,
+    y,
+    w,
+    h,
+    fit,
+    anchorX,
+    anchorY
+  );
+}
+
+export function dt_draw_svg_at(
+  id: number,
+  svg: Uint8Array,
+  x: number,
+  y: number,
+  w: number,
+  h: number,
+  options?: DrawOptions
+) {
+  return dispatch_json(
+    op_dt_draw_svg_at,
+    id,
+    svg,
+    x,
+    y,
+    w,
+    h,
+    ...(options ? [options] : [])
+  );
+}
+
+export function dt_draw_svg_string(
+  id: number,
+  svg: string,
+  x: number,
+  y: number,
+  w: number,
+  h: number,
+  options?: DrawOptions
+) {
+  return dispatch_json(
+    op_dt_draw_svg_string,
+    id,
+    svg,
+    x,
+    y,
+    w,
+    h,
+    ...(options ? [options] : [])
+  );
+}
+
+export function dt_encode_qoi(id: number): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_encode_qoi, id);
+}
+
+export function dt_encode_png_with_level(
+  id: number,
+  level: number
+): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_encode_png_with_level, id, level);
+}
+
+export function dt_encode_png_grayscale(id: number): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_encode_png_grayscale, id);
+}
+
+export function dt_encode_png_alpha_only(id: number): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_encode_png_alpha_only, id);
+}
+
+export function dt_encode_with_icc_profile(
+  id: number,
+  profile: Uint8Array
+): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_encode_with_icc_profile, id, profile);
+}
+
+export function dt_encode_tiff(
+  id: number,
+  compression: TiffCompression
+): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_encode_tiff, id, compression);
+}
+
+export function dt_encode_ico(id: number): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_encode_ico, id);
+}
+
+export function gif_create(id: number, loopCount: number) {
+  return dispatch_json(op_gif_create, id, loopCount);
+}
+
+export function gif_add_frame(id: number, target: number, delay: number) {
+  return dispatch_json(op_gif_add_frame, id, target, delay);
+}
+
+export function gif_finish(id: number): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_gif_finish, id);
+}
+
+export function gif_destroy(id: number) {
+  return dispatch_json(op_gif_destroy, id);
+}
+
+export function apng_create(id: number) {
+  return dispatch_json(op_apng_create, id);
+}
+
+export function apng_add_frame(
+  id: number,
+  target: number,
+  delayNum: number,
+  delayDenom: number
+) {
+  return dispatch_json(op_apng_add_frame, id, target, delayNum, delayDenom);
+}
+
+export function apng_finish(id: number): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_apng_finish, id);
+}
+
+export function apng_destroy(id: number) {
+  return dispatch_json(op_apng_destroy, id);
+}
+
+export function dt_render_to_pdf_page(
+  id: number,
+  dpi: number
+): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_render_to_pdf_page, id, dpi);
+}
+
+export function dt_generate_qr_code(
+  id: number,
+  x: number,
+  y: number,
+  cellSize: number,
+  data: string,
+  fg: ISource,
+  bg: ISource
+) {
+  const [fgJson, fgImage] = _src_args(fg);
+  const [bgJson, bgImage] = _src_args(bg);
+  return dispatch_json(
+    op_dt_generate_qr_code,
+    id,
+    x,
+    y,
+    cellSize,
+    data,
+    fgJson,
+    bgJson,
+    ..._src_pair_images(fgImage, bgImage)
+  );
+}
+
+export function dt_get_histogram(id: number): OpResponse<Histogram> {
+  return dispatch_json(op_dt_get_histogram, id);
+}
+
+export function dt_auto_levels(id: number) {
+  return dispatch_json(op_dt_auto_levels, id);
+}
+
+export function dt_get_average_color(
+  id: number,
+  rect?: [number, number, number, number]
+): OpResponse<AverageColor> {
+  return dispatch_json(op_dt_get_average_color, id, ...(rect ?? []));
+}
+
+export function dt_get_dominant_color(
+  id: number,
+  quantizeBits: number
+): OpResponse<DominantColor> {
+  return dispatch_json(op_dt_get_dominant_color, id, quantizeBits);
+}
+
+export function dt_scan_flood_fill(
+  id: number,
+  x: number,
+  y: number,
+  tolerance: number,
+  src: ISource
+) {
+  return dispatch_json(
+    op_dt_scan_flood_fill,
+    id,
+    x,
+    y,
+    tolerance,
+    ..._src_args(src)
+  );
+}
+
+export function dt_apply_perspective_warp(
+  id: number,
+  srcQuad: number[],
+  dstQuad: number[]
+) {
+  return dispatch_json(op_dt_apply_perspective_warp, id, srcQuad, dstQuad);
+}
+
+export function dt_swirl(
+  id: number,
+  cx: number,
+  cy: number,
+  radius: number,
+  angle: number
+) {
+  return dispatch_json(op_dt_swirl, id, cx, cy, radius, angle);
+}
+
+export function dt_wave_distortion(
+  id: number,
+  ampX: number,
+  freqX: number,
+  ampY: number,
+  freqY: number,
+  phaseX: number,
+  phaseY: number
+) {
+  return dispatch_json(
+    op_dt_wave_distortion,
+    id,
+    ampX,
+    freqX,
+    ampY,
+    freqY,
+    phaseX,
+    phaseY
+  );
+}
+
+export function dt_get_channel(
+  id: number,
+  channel: Channel
+): OpResponse<number> {
+  return dispatch_json(op_dt_get_channel, id, channel);
+}
+
+export function dt_set_channel(id: number, channel: Channel, src: number) {
+  return dispatch_json(op_dt_set_channel, id, channel, src);
+}
+
+export function dt_mask_with_target(id: number, mask: number) {
+  return dispatch_json(op_dt_mask_with_target, id, mask);
+}
+
+export function dt_premultiply(id: number) {
+  return dispatch_json(op_dt_premultiply, id);
+}
+
+export function dt_unpremultiply(id: number) {
+  return dispatch_json(op_dt_unpremultiply, id);
+}
+
+export function dt_convert_to_linear(id: number) {
+  return dispatch_json(op_dt_convert_to_linear, id);
+}
+
+export function dt_convert_to_srgb(id: number) {
+  return dispatch_json(op_dt_convert_to_srgb, id);
+}
+
+export function dt_scale_lanczos(id: number, width: number, height: number) {
+  return dispatch_json(op_dt_scale_lanczos, id, width, height);
+}
+
+export function dt_draw_image_with_color_key(
+  id: number,
+  img: Uint8Array,
+  x: number,
+  y: number,
+  key: IColor,
+  tolerance: number
+) {
+  return dispatch_json(
+    op_dt_draw_image_with_color_key,
+    id,
+    img,
+    x,
+    y,
+    key.r,
+    key.g,
+    key.b,
+    tolerance
+  );
+}
+
+export function dt_nine_slice_fill(
+  id: number,
+  img: Uint8Array,
+  insets: Insets,
+  x: number,
+  y: number,
+  w: number,
+  h: number
+) {
+  return dispatch_json(op_dt_nine_slice_fill, id, img, insets, x, y, w, h);
+}
+
+export function dt_draw_checkerboard(
+  id: number,
+  x: number,
+  y: number,
+  w: number,
+  h: number,
+  cellSize: number,
+  colorA: IColor,
+  colorB: IColor
+) {
+  return dispatch_json(
+    op_dt_draw_checkerboard,
+    id,
+    x,
+    y,
+    w,
+    h,
+    cellSize,
+    colorA,
+    colorB
+  );
+}
+
+export function dt_draw_grid(
+  id: number,
+  x: number,
+  y: number,
+  w: number,
+  h: number,
+  cols: number,
+  rows: number,
+  src: ISource,
+  stroke: StrokeStyle
+) {
+  const [json, ...image] = _src_args(src);
+  return dispatch_json(
+    op_dt_draw_grid,
+    id,
+    x,
+    y,
+    w,
+    h,
+    cols,
+    rows,
+    json,
+    stroke,
+    ...image
+  );
+}
+
+export function dt_snapshot(id: number, snapshot: number) {
+  return dispatch_json(op_dt_snapshot, id, snapshot);
+}
+
+export function dt_draw_snapshot_at(
+  id: number,
+  snapshot: number,
+  x: number,
+  y: number
+) {
+  return dispatch_json(op_dt_draw_snapshot_at, id, snapshot, x, y);
+}
+
+export function snapshot_destroy(id: number) {
+  return dispatch_json(op_snapshot_destroy, id);
+}
+
+export function dt_render_shadow_map(
+  path: PathData,
+  sigma: number,
+  width: number,
+  height: number,
+  dst: number
+) {
+  return dispatch_json(
+    op_dt_render_shadow_map,
+    _fix_path(path),
+    sigma,
+    width,
+    height,
+    dst
+  );
+}
+
+export function dt_generate_mipmap_chain(id: number): OpResponse<MipLevel[]> {
+  return dispatch_json(op_dt_generate_mipmap_chain, id);
+}
+
+export function dt_draw_mipmap_at(
+  chain: number[],
+  id: number,
+  x: number,
+  y: number,
+  w: number,
+  h: number
+) {
+  return dispatch_json(op_dt_draw_mipmap_at, chain, id, x, y, w, h);
+}
+
+export function dt_path_as_clip_region(id: number, path: PathData) {
+  return dispatch_json(op_dt_path_as_clip_region, id, _fix_path(path));
+}
+
+export function dt_svg_path_as_clip_region(id: number, d: string) {
+  return dispatch_json(op_dt_svg_path_as_clip_region, id, d);
+}
+
+export function path_apply_transform(
+  path: PathData,
+  matrix: number[]
+): OpResponse<PathData> {
+  return dispatch_json(op_path_apply_transform, _fix_path(path), matrix);
+}
+
+export function path_reverse(path: PathData): OpResponse<PathData> {
+  return dispatch_json(op_path_reverse, _fix_path(path));
+}
+
+export function path_offset(
+  path: PathData,
+  distance: number
+): OpResponse<PathData> {
+  return dispatch_json(op_path_offset, _fix_path(path), distance);
+}
+
+export function dt_fill_gradient_rect(
+  id: number,
+  x: number,
+  y: number,
+  w: number,
+  h: number,
+  src: ISource
+) {
+  return dispatch_json(
+    op_dt_fill_gradient_rect,
+    id,
+    x,
+    y,
+    w,
+    h,
+    ..._src_args(src)
+  );
+}
+
+export function dt_draw_image_at_subpixel(
+  id: number,
+  img: Uint8Array,
+  x: number,
+  y: number
+) {
+  return dispatch_json(op_dt_draw_image_at_subpixel, id, img, x, y);
+}
+
+export function dt_encode_png_optimized(
+  id: number,
+  level: number
+): OpResponse<Uint8Array> {
+  return dispatch_bytes(op_dt_encode_png_optimized, id, level);
+}
+
+export function dt_canvas_to_data_url(
+  id: number,
+  format: ImageFormat,
+  quality: number
+): OpResponse<string> {
+  return dispatch_json(op_dt_canvas_to_data_url, id, format, quality);
+}
+
+export function dt_draw_progress_bar(
+  id: number,
+  x: number,
+  y: number,
+  w: number,
+  h: number,
+  progress: number,
+  bg: ISource,
+  fill: ISource,
+  radius: number
+) {
+  const [bgJson, bgImage] = _src_args(bg);
+  const [fillJson, fillImage] = _src_args(fill);
+  return dispatch_json(
+    op_dt_draw_progress_bar,
+    id,
+    x,
+    y,
+    w,
+    h,
+    progress,
+    bgJson,
+    fillJson,
+    radius,
+    ..._src_pair_images(bgImage, fillImage)
+  );
+}
+
+export function dt_draw_image_batch(
+  id: number,
+  draws: ImageDraw[],
+  images: Uint8Array[]
+) {
+  return dispatch_json(op_dt_draw_image_batch, id, draws, ...images);
+}
+
+export function dt_blend_mode_fill_rect(
+  id: number,
+  x: number,
+  y: number,
+  w: number,
+  h: number,
+  blend: BlendMode,
+  src: ISource
+) {
+  return dispatch_json(
+    op_dt_blend_mode_fill_rect,
+    id,
+    x,
+    y,
+    w,
+    h,
+    JSON.stringify(blend),
+    ..._src_args(src)
+  );
+}
+
+export function dt_find_edges(id: number, threshold: number) {
+  return dispatch_json(op_dt_find_edges, id, threshold);
+}
+
+export function dt_emboss(id: number, angle: number) {
+  return dispatch_json(op_dt_emboss, id, angle);
+}
+
+export function dt_resize(
+  id: number,
+  width: number,
+  height: number,
+  filter: ResizeFilter
+) {
+  return dispatch_json(op_dt_resize, id, width, height, filter);
+}
+
+export function dt_expand_canvas(
+  id: number,
+  top: number,
+  right: number,
+  bottom: number,
+  left: number,
+  src: ISource
+) {
+  return dispatch_json(
+    op_dt_expand_canvas,
+    id,
+    top,
+    right,
+    bottom,
+    left,
+    ..._src_args(src)
+  );
+}
+
+export function dt_trim_transparent(
+  id: number,
+  threshold: number
+): OpResponse<TrimRect> {
+  return dispatch_json(op_dt_trim_transparent, id, threshold);
+}
+
+export function dt_swap(a: number, b: number) {
+  return dispatch_json(op_dt_swap, a, b);
+}
+
+export function dt_metadata_set(
+  id: number,
+  key: string,
+  value: string
+): OpResponse {
+  return dispatch_json(op_dt_metadata_set, id, key, value);
+}
+
+export function dt_metadata_get(
+  id: number,
+  key: string
+): OpResponse<string | undefined> {
+  return dispatch_json(op_dt_metadata_get, id, key);
+}
+
+export function dt_metadata_get_all(
+  id: number
+): OpResponse<Record<string, string>> {
+  return dispatch_json(op_dt_metadata_get_all, id);
 }
@@ -11,7 +11,10 @@ export type SourceType =
   | "Solid"
   | "LinearGradient"
   | "RadialGradient"
-  | "TwoCircleRadialGradient";
+  | "TwoCircleRadialGradient"
+  | "ConicGradient"
+  | "Pattern"
+  | "Checkerboard";
 
 export enum Spread {
   Pad = "Pad",
@@ -62,6 +65,22 @@ export enum BlendMode {
   Luminosity = "Luminostiy",
 }
 
+export enum AntialiasMode {
+  None = "None",
+  Gray = "Gray",
+}
+
+export interface DrawOptions {
+  blend_mode?: BlendMode;
+  alpha?: number;
+  antialias?: AntialiasMode;
+}
+
+export enum FillRule {
+  NonZero = "NonZero",
+  EvenOdd = "EvenOdd",
+}
+
 export interface IColor {
   r: number;
   g: number;
@@ -89,8 +108,83 @@ export interface ISource {
   radius2?: number | null;
   spread?: Spread | null;
   gradient?: IGradient | null;
+  start_angle?: number | null;
+  transform?: number[] | null;
+  size?: number | null;
+  color_a?: IColor | null;
+  color_b?: IColor | null;
+  image?: Uint8Array;
+}
+
+export type ImageFit = "contain" | "cover" | "fill";
+
+export type TiffCompression = "none" | "lzw" | "deflate";
+
+export type TextAlign = "left" | "center" | "right";
+
+export interface TextMetrics {
+  width: number;
+  height: number;
+  ascent: number;
+  descent: number;
+  line_height: number;
+}
+
+export interface Histogram {
+  r: number[];
+  g: number[];
+  b: number[];
+  a: number[];
+}
+
+export interface AverageColor {
+  r: number;
+  g: number;
+  b: number;
+  a: number;
+}
+
+export interface DominantColor {
+  r: number;
+  g: number;
+  b: number;
+}
+
+export interface Insets {
+  top: number;
+  right: number;
+  bottom: number;
+  left: number;
 }
 
+export interface MipLevel {
+  id: number;
+  width: number;
+  height: number;
+}
+
+export interface ImageDraw {
+  image_idx: number;
+  x: number;
+  y: number;
+  w?: number;
+  h?: number;
+  options?: DrawOptions;
+}
+
+export interface TrimRect {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+}
+
+export type Channel = "r" | "g" | "b" | "a";
+
+export type ImageFormat = "png" | "jpeg" | "webp";
+
+export type ResizeFilter = "nearest" | "bilinear" | "lanczos";
+
 export interface Path {
   path_type: PathType;
   linear?: number[] | null;
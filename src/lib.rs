@@ -1,4 +1,4 @@
-use raqote::{DrawTarget, Source, DrawOptions, SolidSource, Color, Image, Path, PathBuilder, Gradient, Spread, Point, GradientStop, StrokeStyle, LineCap, LineJoin, Transform, IntRect, BlendMode};
+use raqote::{DrawTarget, Source, DrawOptions, SolidSource, Color, Image, Path, PathBuilder, Gradient, Spread, Point, GradientStop, StrokeStyle, LineCap, LineJoin, Transform, IntRect, BlendMode, ExtendMode, FilterMode, AntialiasMode, Winding, Mask, PathOp};
 use std::collections::HashMap;
 use deno_core::plugin_api::Interface;
 use deno_core::{ZeroCopyBuf, Op};
@@ -6,15 +6,38 @@ use std::str::FromStr;
 use std::cell::RefCell;
 use deno_core::serde::Deserialize;
 use image::{GenericImageView};
+use deno_core::serde::Serialize;
+use deno_core::serde::de::DeserializeOwned;
+use std::panic::{self, AssertUnwindSafe};
 use std::io::Read;
 use std::env::temp_dir;
 use euclid::{Point2D, UnknownUnit};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use lazy_static::lazy_static;
 
 thread_local! {
     static TARGETS: RefCell<HashMap<u32, DrawTarget>> = RefCell::new(HashMap::new());
+    // (clip depth, layer depth) for each target, tracked by the push/pop ops.
+    static STACK_DEPTHS: RefCell<HashMap<u32, (u32, u32)>> = RefCell::new(HashMap::new());
+    static GRADIENTS: RefCell<HashMap<u32, PaintSource>> = RefCell::new(HashMap::new());
+    static IMAGES: RefCell<HashMap<u32, JsonImage>> = RefCell::new(HashMap::new());
+    static FONTS: RefCell<HashMap<u32, LoadedFont>> = RefCell::new(HashMap::new());
+    static GIFS: RefCell<HashMap<u32, GifState>> = RefCell::new(HashMap::new());
+    static APNGS: RefCell<HashMap<u32, ApngState>> = RefCell::new(HashMap::new());
+    // (width, height, pixels); shared so drawing does not copy the data.
+    static SNAPSHOTS: RefCell<HashMap<u32, (u32, u32, Arc<Box<[u32]>>)>> = RefCell::new(HashMap::new());
+    // Free-form key/value labels attached to targets, for debugging.
+    static METADATA: RefCell<HashMap<u32, HashMap<String, String>>> = RefCell::new(HashMap::new());
 }
 
-#[derive(Deserialize)]
+const MAX_TARGET_DIMENSION: i32 = 16384;
+
+lazy_static! {
+    static ref NEXT_TARGET_ID: AtomicU32 = AtomicU32::new(0);
+}
+
+#[derive(Deserialize, Serialize)]
 enum JsonPathType {
     Move,
     Quad,
@@ -76,7 +99,7 @@ fn stroke_style_from_json(json: JsonStrokeStyle) -> StrokeStyle {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct JsonPath {
     path_type: JsonPathType,
     linear: Option<[f32; 2]>,
@@ -85,11 +108,123 @@ struct JsonPath {
     arc: Option<[f32; 5]>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct JsonPathData {
     steps: Vec<JsonPath>
 }
 
+// Splits a path into polylines, one per subpath, with each curve replaced by
+// `curve_steps` straight segments. The flag marks subpaths ending in a close.
+fn flatten_path(path: &Path, curve_steps: usize) -> Vec<(Vec<Point>, bool)> {
+    let mut subpaths: Vec<(Vec<Point>, bool)> = Vec::new();
+    let mut start = Point::new(0., 0.);
+    for op in &path.ops {
+        let current = match *op {
+            PathOp::MoveTo(p) => {
+                start = p;
+                subpaths.push((vec![p], false));
+                continue;
+            }
+            PathOp::Close => {
+                if let Some(subpath) = subpaths.last_mut() {
+                    subpath.1 = true;
+                }
+                continue;
+            }
+            _ => {
+                // Drawing on after a close starts again from the subpath start.
+                if subpaths.last().map_or(true, |subpath| subpath.1) {
+                    subpaths.push((vec![start], false));
+                }
+                *subpaths.last().unwrap().0.last().unwrap()
+            }
+        };
+        let points = &mut subpaths.last_mut().unwrap().0;
+        match *op {
+            PathOp::LineTo(p) => points.push(p),
+            PathOp::QuadTo(c, p) => {
+                for i in 1..=curve_steps {
+                    let t = i as f32 / curve_steps as f32;
+                    let mt = 1. - t;
+                    points.push(Point::new(
+                        mt * mt * current.x + 2. * mt * t * c.x + t * t * p.x,
+                        mt * mt * current.y + 2. * mt * t * c.y + t * t * p.y,
+                    ));
+                }
+            }
+            PathOp::CubicTo(c1, c2, p) => {
+                for i in 1..=curve_steps {
+                    let t = i as f32 / curve_steps as f32;
+                    let mt = 1. - t;
+                    points.push(Point::new(
+                        mt * mt * mt * current.x + 3. * mt * mt * t * c1.x + 3. * mt * t * t * c2.x + t * t * t * p.x,
+                        mt * mt * mt * current.y + 3. * mt * mt * t * c1.y + 3. * mt * t * t * c2.y + t * t * t * p.y,
+                    ));
+                }
+            }
+            PathOp::MoveTo(_) | PathOp::Close => {}
+        }
+    }
+    subpaths
+}
+
+// Polyline approximation of a path for finding points at a distance along it.
+struct PathMeasure {
+    // (start, end, distance along the path at start)
+    segments: Vec<(Point, Point, f32)>,
+    length: f32
+}
+
+impl PathMeasure {
+    const CURVE_STEPS: usize = 16;
+
+    fn new(path: &Path) -> PathMeasure {
+        let mut measure = PathMeasure { segments: Vec::new(), length: 0. };
+        for (points, closed) in flatten_path(path, Self::CURVE_STEPS) {
+            for pair in points.windows(2) {
+                measure.push(pair[0], pair[1]);
+            }
+            if closed {
+                measure.push(*points.last().unwrap(), points[0]);
+            }
+        }
+        measure
+    }
+
+    fn push(&mut self, from: Point, to: Point) {
+        let len = (to - from).length();
+        if len > 0. {
+            self.segments.push((from, to, self.length));
+            self.length += len;
+        }
+    }
+
+    // Point and tangent angle in radians at `distance` along the path.
+    fn pos_tan(&self, distance: f32) -> Option<(Point, f32)> {
+        if distance < 0. || distance > self.length {
+            return None;
+        }
+        let &(from, to, start) = self.segments.iter()
+            .take_while(|seg| seg.2 <= distance)
+            .last()?;
+        let d = to - from;
+        let t = (distance - start) / d.length();
+        Some((from + d * t.min(1.), d.y.atan2(d.x)))
+    }
+}
+
+fn path_to_json(path: &Path) -> JsonPathData {
+    let step = |path_type, linear, quad, cubic| JsonPath { path_type, linear, quad, cubic, arc: None };
+    let steps = path.ops.iter().map(|op| match *op {
+        PathOp::MoveTo(p) => step(JsonPathType::Move, Some([p.x, p.y]), None, None),
+        PathOp::LineTo(p) => step(JsonPathType::Line, Some([p.x, p.y]), None, None),
+        PathOp::QuadTo(c, p) => step(JsonPathType::Quad, None, Some([c.x, c.y, p.x, p.y]), None),
+        PathOp::CubicTo(c1, c2, p) => step(JsonPathType::Cubic, None, None, Some([c1.x, c1.y, c2.x, c2.y, p.x, p.y])),
+        PathOp::Close => step(JsonPathType::Close, None, None, None),
+    }).collect();
+    JsonPathData { steps }
+}
+
 #[derive(Deserialize)]
 struct JsonColor {
     r: u8,
@@ -143,7 +278,10 @@ enum JsonSourceType {
     Solid,
     LinearGradient,
     RadialGradient,
-    TwoCircleRadialGradient
+    TwoCircleRadialGradient,
+    ConicGradient,
+    Pattern,
+    Checkerboard
 }
 
 #[derive(Deserialize)]
@@ -157,7 +295,21 @@ struct JsonSource {
     center2: Option<[f32; 2]>,
     radius2: Option<f32>,
     spread: Option<JsonSpread>,
-    gradient: Option<JsonGradient>
+    gradient: Option<JsonGradient>,
+    start_angle: Option<f32>,
+    transform: Option<[f32; 6]>,
+    size: Option<f32>,
+    color_a: Option<JsonColor>,
+    color_b: Option<JsonColor>
+}
+
+#[derive(Deserialize)]
+struct JsonFillRect {
+    x: f32,
+    y: f32,
+    w: f32,
+    h: f32,
+    src: JsonSource
 }
 
 fn point_from_json(v: [f32; 2]) -> Point {
@@ -229,6 +381,82 @@ fn blend_from_json(json: JsonBlendMode) -> BlendMode {
     }
 }
 
+#[derive(Deserialize)]
+enum JsonAntialiasMode {
+    None,
+    Gray
+}
+
+#[derive(Deserialize)]
+struct JsonDrawOptions {
+    blend_mode: Option<JsonBlendMode>,
+    alpha: Option<f32>,
+    antialias: Option<JsonAntialiasMode>
+}
+
+fn draw_options_from_json(json: JsonDrawOptions) -> DrawOptions {
+    let mut options = DrawOptions::new();
+    if let Some(blend) = json.blend_mode {
+        options.blend_mode = blend_from_json(blend);
+    }
+    if let Some(alpha) = json.alpha {
+        options.alpha = alpha;
+    }
+    if let Some(antialias) = json.antialias {
+        options.antialias = match antialias {
+            JsonAntialiasMode::None => AntialiasMode::None,
+            JsonAntialiasMode::Gray => AntialiasMode::Gray,
+        };
+    }
+    options
+}
+
+#[derive(Deserialize)]
+enum JsonFillRule {
+    NonZero,
+    EvenOdd
+}
+
+fn winding_from_json(json: JsonFillRule) -> Winding {
+    match json {
+        JsonFillRule::NonZero => Winding::NonZero,
+        JsonFillRule::EvenOdd => Winding::EvenOdd,
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonFillCommand {
+    path: JsonPathData,
+    src: JsonSource,
+    fill_rule: Option<JsonFillRule>,
+    options: Option<JsonDrawOptions>
+}
+
+// Image commands refer to the image buffers passed after the command list.
+#[derive(Deserialize)]
+#[serde(tag = "op", content = "args", rename_all = "snake_case")]
+enum JsonCommand {
+    FillRect(JsonFillRect),
+    Fill(JsonFillCommand),
+    Stroke(JsonStrokeCommand),
+    DrawImage { image: usize, x: f32, y: f32, w: Option<f32>, h: Option<f32> },
+    PushClip { path: JsonPathData },
+    PushClipRect { x1: f32, y1: f32, x2: f32, y2: f32 },
+    PopClip,
+    PushLayer { opacity: f32, blend_mode: Option<JsonBlendMode> },
+    PopLayer,
+    SetTransform { transform: [f32; 6] },
+    Clear { color: JsonColor },
+}
+
+#[derive(Deserialize)]
+struct JsonStrokeCommand {
+    path: JsonPathData,
+    src: JsonSource,
+    stroke: JsonStrokeStyle,
+    options: Option<JsonDrawOptions>
+}
+
 #[no_mangle]
 pub fn deno_plugin_init(interface: &mut dyn Interface) {
     interface.register_op("op_new_draw_target", op_new_draw_target);
@@ -247,405 +475,1127 @@ pub fn deno_plugin_init(interface: &mut dyn Interface) {
     interface.register_op("op_dt_draw_image_with_size_at", op_dt_draw_image_with_size_at);
     interface.register_op("op_dt_push_clip_rect", op_dt_push_clip_rect);
     interface.register_op("op_dt_push_clip", op_dt_push_clip);
+    interface.register_op("op_dt_path_as_clip_region", op_dt_push_clip);
     interface.register_op("op_dt_pop_clip", op_dt_pop_clip);
     interface.register_op("op_dt_pop_layer", op_dt_pop_layer);
     interface.register_op("op_dt_push_layer", op_dt_push_layer);
     interface.register_op("op_dt_push_layer_with_blend", op_dt_push_layer_with_blend);
+    interface.register_op("op_dt_posterize", op_dt_posterize);
+    interface.register_op("op_dt_flip_horizontal", op_dt_flip_horizontal);
+    interface.register_op("op_dt_flip_vertical", op_dt_flip_vertical);
+    interface.register_op("op_dt_rotate_90", op_dt_rotate_90);
+    interface.register_op("op_dt_rotate_180", op_dt_rotate_180);
+    interface.register_op("op_dt_rotate_270", op_dt_rotate_270);
+    interface.register_op("op_dt_pixelate", op_dt_pixelate);
+    interface.register_op("op_dt_sharpen", op_dt_sharpen);
+    interface.register_op("op_dt_erode", op_dt_erode);
+    interface.register_op("op_dt_dilate", op_dt_dilate);
+    interface.register_op("op_dt_median_filter", op_dt_median_filter);
+    interface.register_op("op_dt_tint", op_dt_tint);
+    interface.register_op("op_dt_opacity", op_dt_opacity);
+    interface.register_op("op_dt_apply_lut", op_dt_apply_lut);
+    interface.register_op("op_dt_convolve", op_dt_convolve);
+    interface.register_op("op_dt_color_balance", op_dt_color_balance);
+    interface.register_op("op_new_draw_target_auto", op_new_draw_target_auto);
+    interface.register_op("op_dt_list", op_dt_list);
+    interface.register_op("op_dt_count", op_dt_count);
+    interface.register_op("op_dt_push_clip_rect_float", op_dt_push_clip_rect_float);
+    interface.register_op("op_dt_clip_depth", op_dt_clip_depth);
+    interface.register_op("op_dt_layer_depth", op_dt_layer_depth);
+    interface.register_op("op_dt_pop_all_clips", op_dt_pop_all_clips);
+    interface.register_op("op_dt_pop_all_layers", op_dt_pop_all_layers);
+    interface.register_op("op_gradient_store", op_gradient_store);
+    interface.register_op("op_dt_fill_with_gradient", op_dt_fill_with_gradient);
+    interface.register_op("op_dt_stroke_with_gradient", op_dt_stroke_with_gradient);
+    interface.register_op("op_dt_fill_rect_batch", op_dt_fill_rect_batch);
+    interface.register_op("op_dt_stroke_path_batch", op_dt_stroke_path_batch);
+    interface.register_op("op_dt_fill_path_batch", op_dt_fill_path_batch);
+    interface.register_op("op_dt_execute_command_list", op_dt_execute_command_list);
+    interface.register_op("op_image_decode", op_image_decode);
+    interface.register_op("op_image_destroy", op_image_destroy);
+    interface.register_op("op_dt_draw_cached_image_at", op_dt_draw_cached_image_at);
+    interface.register_op("op_dt_draw_cached_image_with_size_at", op_dt_draw_cached_image_with_size_at);
+    interface.register_op("op_dt_draw_image_with_opacity", op_dt_draw_image_with_opacity);
+    interface.register_op("op_dt_fill_arc", op_dt_fill_arc);
+    interface.register_op("op_dt_stroke_arc", op_dt_stroke_arc);
+    interface.register_op("op_dt_fill_donut", op_dt_fill_donut);
+    interface.register_op("op_dt_draw_regular_polygon", op_dt_draw_regular_polygon);
+    interface.register_op("op_dt_draw_star_polygon", op_dt_draw_star_polygon);
+    interface.register_op("op_dt_draw_arrow", op_dt_draw_arrow);
+    interface.register_op("op_dt_fill_text", op_dt_fill_text);
+    interface.register_op("op_font_load", op_font_load);
+    interface.register_op("op_font_destroy", op_font_destroy);
+    interface.register_op("op_dt_measure_text", op_dt_measure_text);
+    interface.register_op("op_dt_stroke_text", op_dt_stroke_text);
+    interface.register_op("op_dt_fill_text_wrapped", op_dt_fill_text_wrapped);
+    interface.register_op("op_dt_get_text_path", op_dt_get_text_path);
+    interface.register_op("op_dt_draw_text_on_path", op_dt_draw_text_on_path);
+    interface.register_op("op_dt_draw_image_fit", op_dt_draw_image_fit);
+    interface.register_op("op_dt_draw_svg_at", op_dt_draw_svg_at);
+    interface.register_op("op_dt_draw_svg_string", op_dt_draw_svg_string);
+    interface.register_op("op_dt_encode_qoi", op_dt_encode_qoi);
+    interface.register_op("op_dt_encode_png_with_level", op_dt_encode_png_with_level);
+    interface.register_op("op_dt_encode_png_grayscale", op_dt_encode_png_grayscale);
+    interface.register_op("op_dt_encode_png_alpha_only", op_dt_encode_png_alpha_only);
+    interface.register_op("op_dt_encode_with_icc_profile", op_dt_encode_with_icc_profile);
+    interface.register_op("op_dt_encode_tiff", op_dt_encode_tiff);
+    interface.register_op("op_dt_encode_ico", op_dt_encode_ico);
+    interface.register_op("op_gif_create", op_gif_create);
+    interface.register_op("op_gif_add_frame", op_gif_add_frame);
+    interface.register_op("op_gif_finish", op_gif_finish);
+    interface.register_op("op_gif_destroy", op_gif_destroy);
+    interface.register_op("op_apng_create", op_apng_create);
+    interface.register_op("op_apng_add_frame", op_apng_add_frame);
+    interface.register_op("op_apng_finish", op_apng_finish);
+    interface.register_op("op_apng_destroy", op_apng_destroy);
+    interface.register_op("op_dt_render_to_pdf_page", op_dt_render_to_pdf_page);
+    interface.register_op("op_dt_generate_qr_code", op_dt_generate_qr_code);
+    interface.register_op("op_dt_get_histogram", op_dt_get_histogram);
+    interface.register_op("op_dt_auto_levels", op_dt_auto_levels);
+    interface.register_op("op_dt_get_average_color", op_dt_get_average_color);
+    interface.register_op("op_dt_get_dominant_color", op_dt_get_dominant_color);
+    interface.register_op("op_dt_scan_flood_fill", op_dt_scan_flood_fill);
+    interface.register_op("op_dt_apply_perspective_warp", op_dt_apply_perspective_warp);
+    interface.register_op("op_dt_swirl", op_dt_swirl);
+    interface.register_op("op_dt_wave_distortion", op_dt_wave_distortion);
+    interface.register_op("op_dt_get_channel", op_dt_get_channel);
+    interface.register_op("op_dt_set_channel", op_dt_set_channel);
+    interface.register_op("op_dt_mask_with_target", op_dt_mask_with_target);
+    interface.register_op("op_dt_premultiply", op_dt_premultiply);
+    interface.register_op("op_dt_unpremultiply", op_dt_unpremultiply);
+    interface.register_op("op_dt_convert_to_linear", op_dt_convert_to_linear);
+    interface.register_op("op_dt_convert_to_srgb", op_dt_convert_to_srgb);
+    interface.register_op("op_dt_scale_lanczos", op_dt_scale_lanczos);
+    interface.register_op("op_dt_draw_image_with_color_key", op_dt_draw_image_with_color_key);
+    interface.register_op("op_dt_nine_slice_fill", op_dt_nine_slice_fill);
+    interface.register_op("op_dt_draw_checkerboard", op_dt_draw_checkerboard);
+    interface.register_op("op_dt_draw_grid", op_dt_draw_grid);
+    interface.register_op("op_dt_snapshot", op_dt_snapshot);
+    interface.register_op("op_dt_draw_snapshot_at", op_dt_draw_snapshot_at);
+    interface.register_op("op_snapshot_destroy", op_snapshot_destroy);
+    interface.register_op("op_dt_render_shadow_map", op_dt_render_shadow_map);
+    interface.register_op("op_dt_generate_mipmap_chain", op_dt_generate_mipmap_chain);
+    interface.register_op("op_dt_draw_mipmap_at", op_dt_draw_mipmap_at);
+    interface.register_op("op_dt_svg_path_as_clip_region", op_dt_svg_path_as_clip_region);
+    interface.register_op("op_path_apply_transform", op_path_apply_transform);
+    interface.register_op("op_path_reverse", op_path_reverse);
+    interface.register_op("op_path_offset", op_path_offset);
+    interface.register_op("op_dt_fill_gradient_rect", op_dt_fill_gradient_rect);
+    interface.register_op("op_dt_draw_image_at_subpixel", op_dt_draw_image_at_subpixel);
+    interface.register_op("op_dt_encode_png_optimized", op_dt_encode_png_optimized);
+    interface.register_op("op_dt_canvas_to_data_url", op_dt_canvas_to_data_url);
+    interface.register_op("op_dt_draw_progress_bar", op_dt_draw_progress_bar);
+    interface.register_op("op_dt_draw_image_batch", op_dt_draw_image_batch);
+    interface.register_op("op_dt_blend_mode_fill_rect", op_dt_blend_mode_fill_rect);
+    interface.register_op("op_dt_find_edges", op_dt_find_edges);
+    interface.register_op("op_dt_emboss", op_dt_emboss);
+    interface.register_op("op_dt_resize", op_dt_resize);
+    interface.register_op("op_dt_expand_canvas", op_dt_expand_canvas);
+    interface.register_op("op_dt_trim_transparent", op_dt_trim_transparent);
+    interface.register_op("op_dt_swap", op_dt_swap);
+    interface.register_op("op_dt_metadata_set", op_dt_metadata_set);
+    interface.register_op("op_dt_metadata_get", op_dt_metadata_get);
+    interface.register_op("op_dt_metadata_get_all", op_dt_metadata_get_all);
 }
 
-fn get_arg_str(args: &mut [ZeroCopyBuf], idx: usize) -> Result<&str, &str> {
-    let res = args.get(idx);
-    if res.is_none() {
-        Err("not found")
-    } else {
-        let vec = res.unwrap();
-        Ok(std::str::from_utf8(&vec[..]).unwrap())
+#[derive(Debug)]
+enum PluginError {
+    MissingArg(usize),
+    InvalidArg(usize, String),
+    MissingField(&'static str),
+    Json(String),
+    Image(String),
+    Io(String),
+    TargetNotFound(u32),
+    TargetExists(u32),
+    GradientNotFound(u32),
+    ImageNotFound(u32),
+    FontNotFound(u32),
+    GifNotFound(u32),
+    ApngNotFound(u32),
+    SnapshotNotFound(u32),
+    Invalid(String),
+}
+
+impl std::fmt::Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PluginError::MissingArg(idx) => write!(f, "missing argument {}", idx),
+            PluginError::InvalidArg(idx, e) => write!(f, "invalid argument {}: {}", idx, e),
+            PluginError::MissingField(name) => write!(f, "missing field `{}`", name),
+            PluginError::Json(e) => write!(f, "failed to parse json: {}", e),
+            PluginError::Image(e) => write!(f, "image error: {}", e),
+            PluginError::Io(e) => write!(f, "io error: {}", e),
+            PluginError::TargetNotFound(id) => write!(f, "DrawTarget {} not found", id),
+            PluginError::TargetExists(id) => write!(f, "DrawTarget {} already exists", id),
+            PluginError::GradientNotFound(id) => write!(f, "gradient {} not found", id),
+            PluginError::ImageNotFound(id) => write!(f, "image {} not found", id),
+            PluginError::FontNotFound(id) => write!(f, "font {} not found", id),
+            PluginError::GifNotFound(id) => write!(f, "gif {} not found", id),
+            PluginError::ApngNotFound(id) => write!(f, "apng {} not found", id),
+            PluginError::SnapshotNotFound(id) => write!(f, "snapshot {} not found", id),
+            PluginError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<deno_core::serde_json::Error> for PluginError {
+    fn from(e: deno_core::serde_json::Error) -> Self {
+        PluginError::Json(e.to_string())
+    }
+}
+
+impl From<image::ImageError> for PluginError {
+    fn from(e: image::ImageError) -> Self {
+        PluginError::Image(e.to_string())
     }
 }
 
+impl From<std::io::Error> for PluginError {
+    fn from(e: std::io::Error) -> Self {
+        PluginError::Io(e.to_string())
+    }
+}
+
+type PluginResult<T> = Result<T, PluginError>;
+
+fn field<T>(value: Option<T>, name: &'static str) -> PluginResult<T> {
+    value.ok_or(PluginError::MissingField(name))
+}
+
+fn get_arg_str(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<String> {
+    let buf = args.get(idx).ok_or(PluginError::MissingArg(idx))?;
+    String::from_utf8(buf.to_vec()).map_err(|e| PluginError::InvalidArg(idx, e.to_string()))
+}
+
+fn get_arg_json<T: DeserializeOwned>(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<T> {
+    Ok(deno_core::serde_json::from_str(&get_arg_str(args, idx)?)?)
+}
+
+fn get_arg_parsed<T: FromStr>(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<T> where T::Err: std::fmt::Display {
+    T::from_str(&get_arg_str(args, idx)?).map_err(|e| PluginError::InvalidArg(idx, e.to_string()))
+}
+
 struct JsonImage {
     width: u32,
     height: u32,
     data: Box<[u32]>
 }
 
-fn get_arg_img(args: &mut [ZeroCopyBuf], idx: usize) -> Result<JsonImage, &str> {
-    let res = args.get(idx);
-    if res.is_none() {
-        Err("not found")
-    } else {
-        let vec = res.unwrap();
-        let mut res = Vec::<u32>::new();
-        let dec = image::load_from_memory(vec.as_ref()).unwrap();
-        for c in dec.as_rgba8().unwrap().as_raw().chunks(4) {
-            // (A << 24) | (R << 16) | (G << 8) | B
-            res.push((((c[3] as u32) << 24) | ((c[0] as u32) << 16) | ((c[1] as u32) << 8) | (c[2] as u32)) as u32);
-        }
-        Ok(JsonImage { data: res.into_boxed_slice(), width: dec.width(), height: dec.height() })
+fn get_arg_img(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<JsonImage> {
+    get_arg_img_with(args, idx, |_| {})
+}
+
+// Decodes like get_arg_img, letting `f` adjust each RGBA pixel before packing.
+fn get_arg_img_with<F: FnMut(&mut [u8])>(args: &mut [ZeroCopyBuf], idx: usize, mut f: F) -> PluginResult<JsonImage> {
+    let vec = args.get(idx).ok_or(PluginError::MissingArg(idx))?;
+    let mut res = Vec::<u32>::new();
+    let dec = image::load_from_memory(vec.as_ref())?;
+    let mut rgba = dec.to_rgba8().into_raw();
+    for c in rgba.chunks_mut(4) {
+        f(c);
+        // (A << 24) | (R << 16) | (G << 8) | B
+        res.push((((c[3] as u32) << 24) | ((c[0] as u32) << 16) | ((c[1] as u32) << 8) | (c[2] as u32)) as u32);
     }
+    Ok(JsonImage { data: res.into_boxed_slice(), width: dec.width(), height: dec.height() })
 }
 
-fn get_arg_path(args: &mut [ZeroCopyBuf], idx: usize) -> Result<Path, &str> {
-    let res = get_arg_str(args, idx);
-    if res.is_err() {
-        Err("not found")
-    } else {
-        let json_path: JsonPathData = deno_core::serde_json::from_str(res.unwrap()).unwrap();
-        let mut pb = PathBuilder::new();
-        for step in json_path.steps {
-            match step.path_type {
-                JsonPathType::Move => {
-                    let data = step.linear.unwrap();
-                    pb.move_to(data[0], data[1]);
-                }
-                JsonPathType::Line => {
-                    let data = step.linear.unwrap();
-                    pb.line_to(data[0], data[1]);
-                }
-                JsonPathType::Quad => {
-                    let data = step.quad.unwrap();
-                    pb.quad_to(data[0], data[1], data[2], data[3]);
-                }
-                JsonPathType::Rect => {
-                    let data = step.quad.unwrap();
-                    pb.rect(data[0], data[1], data[2], data[3]);
-                }
-                JsonPathType::Cubic => {
-                    let data = step.cubic.unwrap();
-                    pb.cubic_to(data[0], data[1], data[2], data[3], data[4], data[5]);
-                }
-                JsonPathType::Arc => {
-                    let data = step.arc.unwrap();
-                    pb.arc(data[0], data[1], data[2], data[3], data[4]);
-                }
-                JsonPathType::Close => {
-                    pb.close();
-                }
+fn get_arg_path(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<Path> {
+    path_from_json(get_arg_json(args, idx)?)
+}
+
+fn path_from_json(json_path: JsonPathData) -> PluginResult<Path> {
+    let mut pb = PathBuilder::new();
+    for step in json_path.steps {
+        match step.path_type {
+            JsonPathType::Move => {
+                let data = field(step.linear, "linear")?;
+                pb.move_to(data[0], data[1]);
+            }
+            JsonPathType::Line => {
+                let data = field(step.linear, "linear")?;
+                pb.line_to(data[0], data[1]);
+            }
+            JsonPathType::Quad => {
+                let data = field(step.quad, "quad")?;
+                pb.quad_to(data[0], data[1], data[2], data[3]);
+            }
+            JsonPathType::Rect => {
+                let data = field(step.quad, "quad")?;
+                pb.rect(data[0], data[1], data[2], data[3]);
+            }
+            JsonPathType::Cubic => {
+                let data = field(step.cubic, "cubic")?;
+                pb.cubic_to(data[0], data[1], data[2], data[3], data[4], data[5]);
+            }
+            JsonPathType::Arc => {
+                let data = field(step.arc, "arc")?;
+                pb.arc(data[0], data[1], data[2], data[3], data[4]);
+            }
+            JsonPathType::Close => {
+                pb.close();
             }
         }
-        Ok(pb.finish())
     }
+    Ok(pb.finish())
 }
 
-fn get_arg_src(args: &mut [ZeroCopyBuf], idx: usize) -> Result<Source, &str> {
-    let res = get_arg_str(args, idx);
-    if res.is_err() {
-        Err("not found")
-    } else {
-        let val = res.unwrap();
-        let json_res = deno_core::serde_json::from_str(val);
-        if json_res.is_err() {
-            Err("failed to parse json")
-        } else {
-            let json: JsonSource = json_res.unwrap();
-            match json.src_type {
-                JsonSourceType::Solid => {
-                    let v = json.color.unwrap();
-                    Ok(Source::from(color_from_json(v)))
-                }
-                JsonSourceType::LinearGradient => {
-                    let v = json.gradient.unwrap();
-                    let start = point_from_json(json.start.unwrap());
-                    let end = point_from_json(json.end.unwrap());
-                    let spread = json.spread.unwrap();
-                    Ok(Source::new_linear_gradient(gradient_from_json(v), start, end, spread_from_json(spread)))
-                }
-                JsonSourceType::RadialGradient => {
-                    let v = gradient_from_json(json.gradient.unwrap());
-                    let center = point_from_json(json.center.unwrap());
-                    let radius = json.radius.unwrap();
-                    let spread = spread_from_json(json.spread.unwrap());
-                    Ok(Source::new_radial_gradient(v, center, radius, spread))
-                }
-                JsonSourceType::TwoCircleRadialGradient => {
-                    let v = gradient_from_json(json.gradient.unwrap());
-                    let center = point_from_json(json.center.unwrap());
-                    let radius = json.radius.unwrap();
-                    let center2 = point_from_json(json.center2.unwrap());
-                    let radius2 = json.radius2.unwrap();
-                    let spread = spread_from_json(json.spread.unwrap());
-                    Ok(Source::new_two_circle_radial_gradient(v, center, radius, center2, radius2, spread))
-                }
+// Sources that raqote cannot express natively are rendered into a
+// device-sized image when drawn.
+enum PaintSource {
+    Raqote(Source<'static>),
+    Conic { center: Point, start_angle: f32, stops: Vec<JsonGradientStop>, transform: Transform },
+    Pattern(JsonImage, Transform),
+    // Premultiplied colours of the even and odd cells.
+    Checkerboard { size: f32, colors: [u32; 2], transform: Transform },
+}
+
+impl PaintSource {
+    fn draw<T>(&self, target: &mut DrawTarget, f: impl FnOnce(&mut DrawTarget, &Source) -> T) -> T {
+        match self {
+            PaintSource::Raqote(src) => f(target, src),
+            PaintSource::Conic { center, start_angle, stops, transform } => {
+                let data = render_device_source(target, |p| {
+                    let p = transform.transform_point(p);
+                    let angle = (p.y - center.y).atan2(p.x - center.x) - start_angle;
+                    sample_stops(stops, (angle / (2. * std::f32::consts::PI)).rem_euclid(1.))
+                });
+                let img = Image { width: target.width(), height: target.height(), data: &data };
+                let src = Source::Image(img, ExtendMode::Pad, FilterMode::Nearest, *target.get_transform());
+                f(target, &src)
+            }
+            PaintSource::Pattern(pattern, transform) => {
+                let img = Image { width: pattern.width as i32, height: pattern.height as i32, data: &*pattern.data };
+                let src = Source::Image(img, ExtendMode::Repeat, FilterMode::Bilinear, *transform);
+                f(target, &src)
+            }
+            PaintSource::Checkerboard { size, colors, transform } => {
+                let data = render_device_source(target, |p| {
+                    let p = transform.transform_point(p);
+                    let cell = (p.x / size).floor() + (p.y / size).floor();
+                    colors[cell.rem_euclid(2.) as usize]
+                });
+                let img = Image { width: target.width(), height: target.height(), data: &data };
+                let src = Source::Image(img, ExtendMode::Pad, FilterMode::Nearest, *target.get_transform());
+                f(target, &src)
             }
         }
     }
 }
 
-fn get_arg_i32(args: &mut [ZeroCopyBuf], idx: usize) -> Result<i32, &str> {
-    let res = get_arg_str(args, idx);
-    if res.is_err() {
-        Err("not found")
-    } else {
-        Ok(i32::from_str(res.unwrap()).unwrap())
+// Samples a premultiplied pixel at the centre of every device pixel, mapped
+// back into user space. The result is drawn with the target's transform as
+// the image transform so that image space lines up with device space.
+fn render_device_source(target: &DrawTarget, sample: impl Fn(Point) -> u32) -> Vec<u32> {
+    let width = target.width();
+    let height = target.height();
+    let mut data = vec![0u32; (width * height) as usize];
+    let inverse = match target.get_transform().inverse() {
+        Some(inverse) => inverse,
+        None => return data,
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let p = inverse.transform_point(Point::new(x as f32 + 0.5, y as f32 + 0.5));
+            data[(y * width + x) as usize] = sample(p);
+        }
     }
+    data
 }
 
-fn get_arg_f32(args: &mut [ZeroCopyBuf], idx: usize) -> Result<f32, &str> {
-    let res = get_arg_str(args, idx);
-    if res.is_err() {
-        Err("not found")
-    } else {
-        Ok(f32::from_str(res.unwrap()).unwrap())
+// Linearly interpolates unpremultiplied stop colours at t.
+fn sample_stops(stops: &[JsonGradientStop], t: f32) -> u32 {
+    let argb = |c: &JsonColor| [c.a, c.r, c.g, c.b];
+    let (first, last) = match (stops.first(), stops.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return 0,
+    };
+    if t <= first.position {
+        return premultiply(argb(&first.color));
+    }
+    for pair in stops.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+        if t <= b.position {
+            let span = b.position - a.position;
+            let f = if span > 0. { (t - a.position) / span } else { 1. };
+            let (ca, cb) = (argb(&a.color), argb(&b.color));
+            let mut out = [0u8; 4];
+            for (i, c) in out.iter_mut().enumerate() {
+                *c = (ca[i] as f32 + (cb[i] as f32 - ca[i] as f32) * f).round() as u8;
+            }
+            return premultiply(out);
+        }
     }
+    premultiply(argb(&last.color))
 }
 
-fn get_arg_u32(args: &mut [ZeroCopyBuf], idx: usize) -> Result<u32, &str> {
-    let res = get_arg_str(args, idx);
-    if res.is_err() {
-        Err("not found")
-    } else {
-        Ok(u32::from_str(res.unwrap()).unwrap())
+// Pattern sources take their image bytes from the argument at image_idx.
+fn get_arg_src(args: &mut [ZeroCopyBuf], idx: usize, image_idx: usize) -> PluginResult<PaintSource> {
+    let json: JsonSource = get_arg_json(args, idx)?;
+    let pattern = match json.src_type {
+        JsonSourceType::Pattern => Some(get_arg_img(args, image_idx)?),
+        _ => None,
+    };
+    source_from_json(json, pattern)
+}
+
+// [a, b, c, d, e, f] in the same order as canvas setTransform.
+fn transform_from_json(v: [f32; 6]) -> Transform {
+    Transform::row_major(v[0], v[1], v[2], v[3], v[4], v[5])
+}
+
+fn source_from_json(json: JsonSource, pattern: Option<JsonImage>) -> PluginResult<PaintSource> {
+    // Source transforms map user space into source space, so the JSON
+    // transform, which places the source in user space, is inverted.
+    let inverse = match json.transform {
+        Some(v) => transform_from_json(v).inverse()
+            .ok_or_else(|| PluginError::Invalid("source transform is not invertible".to_string()))?,
+        None => Transform::identity(),
+    };
+    let src = match json.src_type {
+        JsonSourceType::Solid => {
+            let v = field(json.color, "color")?;
+            Source::from(color_from_json(v))
+        }
+        JsonSourceType::LinearGradient => {
+            let v = field(json.gradient, "gradient")?;
+            let start = point_from_json(field(json.start, "start")?);
+            let end = point_from_json(field(json.end, "end")?);
+            let spread = field(json.spread, "spread")?;
+            Source::new_linear_gradient(gradient_from_json(v), start, end, spread_from_json(spread))
+        }
+        JsonSourceType::RadialGradient => {
+            let v = gradient_from_json(field(json.gradient, "gradient")?);
+            let center = point_from_json(field(json.center, "center")?);
+            let radius = field(json.radius, "radius")?;
+            let spread = spread_from_json(field(json.spread, "spread")?);
+            Source::new_radial_gradient(v, center, radius, spread)
+        }
+        JsonSourceType::TwoCircleRadialGradient => {
+            let v = gradient_from_json(field(json.gradient, "gradient")?);
+            let center = point_from_json(field(json.center, "center")?);
+            let radius = field(json.radius, "radius")?;
+            let center2 = point_from_json(field(json.center2, "center2")?);
+            let radius2 = field(json.radius2, "radius2")?;
+            let spread = spread_from_json(field(json.spread, "spread")?);
+            Source::new_two_circle_radial_gradient(v, center, radius, center2, radius2, spread)
+        }
+        JsonSourceType::ConicGradient => {
+            let mut stops = field(json.gradient, "gradient")?.stops;
+            stops.sort_by(|a, b| a.position.partial_cmp(&b.position).unwrap_or(std::cmp::Ordering::Equal));
+            return Ok(PaintSource::Conic {
+                center: point_from_json(field(json.center, "center")?),
+                start_angle: field(json.start_angle, "start_angle")?,
+                stops,
+                transform: inverse,
+            });
+        }
+        JsonSourceType::Pattern => {
+            let pattern = pattern.ok_or_else(|| PluginError::Invalid("pattern sources need an image argument".to_string()))?;
+            return Ok(PaintSource::Pattern(pattern, inverse));
+        }
+        JsonSourceType::Checkerboard => {
+            let size = field(json.size, "size")?;
+            if size <= 0. {
+                return Err(PluginError::Invalid("checkerboard size must be positive".to_string()));
+            }
+            let argb = |c: JsonColor| premultiply([c.a, c.r, c.g, c.b]);
+            return Ok(PaintSource::Checkerboard {
+                size,
+                colors: [argb(field(json.color_a, "color_a")?), argb(field(json.color_b, "color_b")?)],
+                transform: inverse,
+            });
+        }
+    };
+    Ok(PaintSource::Raqote(match src {
+        Source::LinearGradient(g, spread, m) => Source::LinearGradient(g, spread, inverse.post_transform(&m)),
+        Source::RadialGradient(g, spread, m) => Source::RadialGradient(g, spread, inverse.post_transform(&m)),
+        Source::TwoCircleRadialGradient(g, spread, c1, r1, c2, r2, m) => {
+            Source::TwoCircleRadialGradient(g, spread, c1, r1, c2, r2, inverse.post_transform(&m))
+        }
+        other => other,
+    }))
+}
+
+fn get_arg_i32(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<i32> {
+    get_arg_parsed(args, idx)
+}
+
+fn get_arg_f32(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<f32> {
+    let v: f32 = get_arg_parsed(args, idx)?;
+    if !v.is_finite() {
+        return Err(PluginError::InvalidArg(idx, "non-finite float".to_string()));
     }
+    Ok(v)
 }
 
-fn get_arg_u8(args: &mut [ZeroCopyBuf], idx: usize) -> Result<u8, &str> {
-    let res = get_arg_str(args, idx);
-    if res.is_err() {
-        Err("not found")
-    } else {
-        Ok(u8::from_str(res.unwrap()).unwrap())
+fn get_arg_f32_or(args: &mut [ZeroCopyBuf], idx: usize, default: f32) -> PluginResult<f32> {
+    if args.len() <= idx {
+        return Ok(default);
     }
+    get_arg_f32(args, idx)
 }
 
-fn op_new_draw_target(
-    _interface: &mut dyn Interface, 
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let width = get_arg_i32(_args, 1).unwrap();
-    let height = get_arg_i32(_args, 2).unwrap();
-    TARGETS.with(|map| {
-        let mut targets = map.borrow_mut();
-        if targets.contains_key(&id) {
-            let res = b"1";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else {
-            let dt = DrawTarget::new(width, height);
-            targets.insert(id, dt);
-            let res = b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())   
-        }
-    })
+fn get_arg_draw_options(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<DrawOptions> {
+    if args.len() <= idx {
+        return Ok(DrawOptions::new());
+    }
+    Ok(draw_options_from_json(get_arg_json(args, idx)?))
 }
 
-fn op_dt_destroy(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id: u32 = get_arg_u32(_args, 0).unwrap();
-    TARGETS.with(|map| {
-        if let Some(_target) = map.borrow_mut().get_mut(&id) {
-            map.borrow_mut().remove(&id);
-            let res= b"0"; 
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
-    })
+fn get_arg_u32(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<u32> {
+    get_arg_parsed(args, idx)
 }
 
-fn op_dt_get_data(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id: u32 = get_arg_u32(_args, 0).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            Op::Sync(target.get_data_u8().to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
-    })
+fn get_arg_u8(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<u8> {
+    get_arg_parsed(args, idx)
 }
 
-fn op_dt_width(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id: u32 = get_arg_u32(_args, 0).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            Op::Sync(target.width().to_string().as_bytes().to_vec().into_boxed_slice())
-        } else { let res= b"n"; Op::Sync(res.to_vec().into_boxed_slice()) }
-    })
+fn get_arg_buf(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<&[u8]> {
+    args.get(idx).map(|buf| &buf[..]).ok_or(PluginError::MissingArg(idx))
 }
 
-fn op_dt_height(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id: u32 = get_arg_u32(_args, 0).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            Op::Sync(target.height().to_string().as_bytes().to_vec().into_boxed_slice())
-        } else { let res= b"n"; Op::Sync(res.to_vec().into_boxed_slice()) }
-    })
+fn ok_response() -> Op {
+    Op::Sync(br#"{"ok":true}"#.to_vec().into_boxed_slice())
 }
 
-fn op_dt_encode(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id: u32 = get_arg_u32(_args, 0).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            let tmp = temp_dir().to_str().unwrap().to_owned();
-            let file_name = format!("{}.png", uuid::Uuid::new_v4());
-            let path = tmp + &file_name;
-            target.write_png(&path).unwrap();
-            let mut buf = Vec::<u8>::new();
-            let mut file = std::fs::File::open(&path).unwrap();
-            file.read_to_end(&mut buf).unwrap();
-            std::fs::remove_file(&path).unwrap();
-            Op::Sync(buf.into_boxed_slice())
-        } else { let res= b"n"; Op::Sync(res.to_vec().into_boxed_slice()) }
-    })
+fn value_response<T: Serialize>(value: T) -> Op {
+    let res = deno_core::serde_json::json!({ "ok": true, "value": value });
+    Op::Sync(res.to_string().into_bytes().into_boxed_slice())
 }
 
-fn op_dt_write_png(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id: u32 = get_arg_u32(_args, 0).unwrap();
-    let path = get_arg_str(_args, 1).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            let written = target.write_png(path);
-            let mut res= b"0";
-            if written.is_err() { res = b"1"; }
-            Op::Sync(res.to_vec().into_boxed_slice())
+// Ops returning raw bytes (pixel data, encoded images) send this object instead
+// on failure; the JS side tells them apart by the leading `{"ok":false`.
+fn error_response(error: &str) -> Op {
+    let res = deno_core::serde_json::json!({ "ok": false, "error": error });
+    Op::Sync(res.to_string().into_bytes().into_boxed_slice())
+}
+
+// Runs an op body, turning both errors and panics into error responses so a
+// bad argument can't take down the whole Deno process.
+fn run_op<F: FnOnce() -> PluginResult<Op>>(op: F) -> Op {
+    match panic::catch_unwind(AssertUnwindSafe(op)) {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => error_response(&e.to_string()),
+        Err(payload) => {
+            let msg = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown".to_string());
+            error_response(&format!("panic: {}", msg))
+        }
+    }
+}
+
+fn new_target(width: i32, height: i32) -> PluginResult<DrawTarget> {
+    if width <= 0 || height <= 0 {
+        return Err(PluginError::Invalid(format!("invalid DrawTarget size {}x{}", width, height)));
+    }
+    if width > MAX_TARGET_DIMENSION || height > MAX_TARGET_DIMENSION {
+        return Err(PluginError::Invalid(format!(
+            "DrawTarget size {}x{} exceeds the {} pixel limit", width, height, MAX_TARGET_DIMENSION
+        )));
+    }
+    Ok(DrawTarget::new(width, height))
+}
+
+enum PaintMode {
+    Fill,
+    Stroke(StrokeStyle),
+}
+
+impl PaintMode {
+    fn paint(&self, target: &mut DrawTarget, path: &Path, src: &PaintSource) {
+        match self {
+            PaintMode::Fill => src.draw(target, |target, src| target.fill(path, src, &DrawOptions::new())),
+            PaintMode::Stroke(stroke) => src.draw(target, |target, src| target.stroke(path, src, stroke, &DrawOptions::new())),
+        }
+    }
+}
+
+// The stroke style is only read when the mode is "stroke".
+fn get_arg_paint_mode(args: &mut [ZeroCopyBuf], idx: usize, stroke_idx: usize) -> PluginResult<PaintMode> {
+    match get_arg_str(args, idx)?.as_str() {
+        "fill" => Ok(PaintMode::Fill),
+        "stroke" => Ok(PaintMode::Stroke(stroke_style_from_json(get_arg_json(args, stroke_idx)?))),
+        other => Err(PluginError::InvalidArg(idx, format!("unknown mode `{}`", other))),
+    }
+}
+
+// Closed path through `count` points evenly spaced by angle around (cx, cy),
+// the first at `rotation` radians.
+fn radial_polygon(cx: f32, cy: f32, count: u32, rotation: f32, radius: impl Fn(u32) -> f32) -> Path {
+    let mut pb = PathBuilder::new();
+    for i in 0..count {
+        let angle = rotation + 2. * std::f32::consts::PI * i as f32 / count as f32;
+        let (x, y) = (cx + radius(i) * angle.cos(), cy + radius(i) * angle.sin());
+        if i == 0 {
+            pb.move_to(x, y);
         } else {
-            let res= b"1";
-            Op::Sync(res.to_vec().into_boxed_slice()) 
+            pb.line_to(x, y);
         }
-    })
+    }
+    pb.close();
+    pb.finish()
 }
 
-fn op_dt_fill_rect(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let x = get_arg_f32(_args, 1).unwrap();
-    let y = get_arg_f32(_args, 2).unwrap();
-    let w = get_arg_f32(_args, 3).unwrap();
-    let h = get_arg_f32(_args, 4).unwrap();
-    let src = get_arg_src(_args, 5).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            target.fill_rect(x, y, w, h, &src, &DrawOptions::new());
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+fn with_depths<T>(id: u32, f: impl FnOnce(&mut (u32, u32)) -> T) -> T {
+    STACK_DEPTHS.with(|map| f(map.borrow_mut().entry(id).or_insert((0, 0))))
+}
+
+fn pop_clip_depth(id: u32) -> PluginResult<()> {
+    with_depths(id, |depths| {
+        if depths.0 == 0 {
+            return Err(PluginError::Invalid("no clip to pop".to_string()));
+        }
+        depths.0 -= 1;
+        Ok(())
     })
 }
 
-fn op_dt_clear(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let a = get_arg_u8(_args, 1).unwrap();
-    let r = get_arg_u8(_args, 2).unwrap();
-    let g = get_arg_u8(_args, 3).unwrap();
-    let b = get_arg_u8(_args, 4).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            target.clear(SolidSource::from(Color::new(a, r, g, b)));
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+fn pop_layer_depth(id: u32) -> PluginResult<()> {
+    with_depths(id, |depths| {
+        if depths.1 == 0 {
+            return Err(PluginError::Invalid("no layer to pop".to_string()));
+        }
+        depths.1 -= 1;
+        Ok(())
     })
 }
 
-fn op_dt_fill(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let path = get_arg_path(_args, 1).unwrap();
-    let src = get_arg_src(_args, 2).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            target.fill(&path, &src, &DrawOptions::new());
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+fn with_gradient<T, F: FnOnce(&PaintSource) -> PluginResult<T>>(id: u32, f: F) -> PluginResult<T> {
+    GRADIENTS.with(|map| {
+        let gradients = map.borrow();
+        let src = gradients.get(&id).ok_or(PluginError::GradientNotFound(id))?;
+        f(src)
     })
 }
 
-fn op_dt_stroke(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let path = get_arg_path(_args, 1).unwrap();
-    let stroke = stroke_style_from_json(deno_core::serde_json::from_str(get_arg_str(_args, 3).unwrap()).unwrap());
-    let src = get_arg_src(_args, 2).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            target.stroke(&path, &src, &stroke, &DrawOptions::new());
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+fn with_image<T, F: FnOnce(&JsonImage) -> PluginResult<T>>(id: u32, f: F) -> PluginResult<T> {
+    IMAGES.with(|map| {
+        let images = map.borrow();
+        let img = images.get(&id).ok_or(PluginError::ImageNotFound(id))?;
+        f(img)
     })
 }
 
-fn op_dt_draw_image_at(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let x = get_arg_f32(_args, 2).unwrap();
-    let y = get_arg_f32(_args, 3).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            let img = get_arg_img(_args, 1).unwrap();
-            target.draw_image_at(x, y, &Image {
-                width: img.width as i32,
-                height: img.height as i32,
-                data: &*img.data
-            }, &DrawOptions::new());
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+fn with_font<T, F: FnOnce(&LoadedFont) -> PluginResult<T>>(id: u32, f: F) -> PluginResult<T> {
+    FONTS.with(|map| {
+        let fonts = map.borrow();
+        let font = fonts.get(&id).ok_or(PluginError::FontNotFound(id))?;
+        f(font)
     })
 }
 
-fn op_dt_draw_image_with_size_at(
-    _interface: &mut dyn Interface,
-    _args: &mut [ZeroCopyBuf],
-) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let x = get_arg_f32(_args, 2).unwrap();
-    let y = get_arg_f32(_args, 3).unwrap();
-    let w = get_arg_f32(_args, 4).unwrap();
-    let h = get_arg_f32(_args, 5).unwrap();
+fn with_target<T, F: FnOnce(&mut DrawTarget) -> PluginResult<T>>(id: u32, f: F) -> PluginResult<T> {
     TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            let img = get_arg_img(_args, 1).unwrap();
-            target.draw_image_with_size_at(x, y, w, h, &Image {
-                width: img.width as i32,
-                height: img.height as i32,
-                data: &*img.data
-            }, &DrawOptions::new());
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+        let mut targets = map.borrow_mut();
+        let target = targets.get_mut(&id).ok_or(PluginError::TargetNotFound(id))?;
+        f(target)
     })
 }
 
-fn op_dt_set_transform(
+fn unpremultiply(pixel: u32) -> [u8; 4] {
+    let a = (pixel >> 24) as u32;
+    if a == 0 {
+        return [0, 0, 0, 0];
+    }
+    let un = |c: u32| (((c & 0xff) * 255 + a / 2) / a).min(255) as u8;
+    [a as u8, un(pixel >> 16), un(pixel >> 8), un(pixel)]
+}
+
+// Straight-alpha RGBA8 bytes, the layout most encoders expect.
+fn target_rgba(target: &DrawTarget) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(target.get_data().len() * 4);
+    for &pixel in target.get_data() {
+        let [a, r, g, b] = unpremultiply(pixel);
+        rgba.extend_from_slice(&[r, g, b, a]);
+    }
+    rgba
+}
+
+fn encode_png(width: u32, height: u32, color: png::ColorType, data: &[u8], compression: png::Compression) -> PluginResult<Vec<u8>> {
+    encode_png_with_chunks(width, height, color, data, compression, &[])
+}
+
+// Extra chunks are written between IHDR and the image data.
+fn encode_png_with_chunks(
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+    data: &[u8],
+    compression: png::Compression,
+    chunks: &[([u8; 4], Vec<u8>)],
+) -> PluginResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut buf, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(compression);
+        let mut writer = encoder.write_header().map_err(|e| PluginError::Image(e.to_string()))?;
+        for (name, chunk) in chunks {
+            writer.write_chunk(*name, chunk).map_err(|e| PluginError::Image(e.to_string()))?;
+        }
+        writer.write_image_data(data).map_err(|e| PluginError::Image(e.to_string()))?;
+    }
+    Ok(buf)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Prefixes each row with the PNG filter giving the smallest sum of absolute
+// residuals, the heuristic suggested by the PNG specification.
+fn png_filter_rows(data: &[u8], width: usize, bpp: usize) -> Vec<u8> {
+    let stride = width * bpp;
+    if stride == 0 {
+        return Vec::new();
+    }
+    let zero = vec![0u8; stride];
+    let mut candidate = vec![0u8; stride];
+    let mut best = vec![0u8; stride];
+    let mut out = Vec::with_capacity(data.len() + data.len() / stride);
+    for (y, row) in data.chunks_exact(stride).enumerate() {
+        let prev = if y == 0 { &zero[..] } else { &data[(y - 1) * stride..y * stride] };
+        let mut best_filter = 0;
+        let mut best_cost = u64::MAX;
+        for filter in 0..5u8 {
+            for i in 0..stride {
+                let a = if i >= bpp { row[i - bpp] } else { 0 };
+                let c = if i >= bpp { prev[i - bpp] } else { 0 };
+                let predictor = match filter {
+                    0 => 0,
+                    1 => a,
+                    2 => prev[i],
+                    3 => ((a as u16 + prev[i] as u16) / 2) as u8,
+                    _ => paeth(a, prev[i], c),
+                };
+                candidate[i] = row[i].wrapping_sub(predictor);
+            }
+            let cost: u64 = candidate.iter().map(|&v| (v as i8).unsigned_abs() as u64).sum();
+            if cost < best_cost {
+                best_cost = cost;
+                best_filter = filter;
+                std::mem::swap(&mut best, &mut candidate);
+            }
+        }
+        out.push(best_filter);
+        out.extend_from_slice(&best);
+    }
+    out
+}
+
+// zlib stream at a zlib-style level: 0 writes stored blocks, 1-9 follow the
+// hash chain lengths and lazy match thresholds of zlib's configuration table.
+fn zlib_compress(data: &[u8], level: u8) -> Vec<u8> {
+    use deflate::{CompressionOptions, MatchingType, SpecialOptions};
+    let (max_hash_checks, lazy_if_less_than, matching_type) = match level {
+        0 => return zlib_stored(data),
+        1 => (4, 0, MatchingType::Greedy),
+        2 => (8, 0, MatchingType::Greedy),
+        3 => (32, 0, MatchingType::Greedy),
+        4 => (16, 4, MatchingType::Lazy),
+        5 => (32, 16, MatchingType::Lazy),
+        6 => (128, 16, MatchingType::Lazy),
+        7 => (256, 32, MatchingType::Lazy),
+        8 => (1024, 128, MatchingType::Lazy),
+        _ => (4096, 258, MatchingType::Lazy),
+    };
+    let options = CompressionOptions {
+        max_hash_checks,
+        lazy_if_less_than,
+        matching_type,
+        special: SpecialOptions::Normal,
+    };
+    deflate::deflate_bytes_zlib_conf(data, options)
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    if data.is_empty() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    let mut blocks = data.chunks(0xffff).peekable();
+    while let Some(block) = blocks.next() {
+        // BFINAL on the last block, BTYPE 00 (stored).
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+fn premultiply(argb: [u8; 4]) -> u32 {
+    let a = argb[0] as u32;
+    let pre = |c: u8| (c as u32 * a + 127) / 255;
+    (a << 24) | (pre(argb[1]) << 16) | (pre(argb[2]) << 8) | pre(argb[3])
+}
+
+// Rotates by a quarter turn into a new DrawTarget with width and height swapped.
+fn rotate_quarter(target: &DrawTarget, clockwise: bool) -> DrawTarget {
+    let width = target.width() as usize;
+    let height = target.height() as usize;
+    let mut rotated = DrawTarget::new(height as i32, width as i32);
+    let src = target.get_data();
+    let dst = rotated.get_data_mut();
+    for y in 0..height {
+        for x in 0..width {
+            let idx = if clockwise { x * height + (height - 1 - y) } else { (width - 1 - x) * height + y };
+            dst[idx] = src[y * width + x];
+        }
+    }
+    rotated
+}
+
+// Per-channel min/max over a square window, applied as a horizontal pass then a
+// vertical pass. Both keep premultiplied channels at or below alpha.
+fn morphology(target: &mut DrawTarget, radius: usize, pick: fn(u8, u8) -> u8) {
+    let width = target.width() as usize;
+    let height = target.height() as usize;
+    let data = target.get_data_mut();
+    let reduce = |pixels: &mut dyn Iterator<Item = u32>| {
+        let mut out = [0u8; 4];
+        for (n, pixel) in pixels.enumerate() {
+            for (i, c) in out.iter_mut().enumerate() {
+                let v = (pixel >> (24 - i * 8)) as u8;
+                *c = if n == 0 { v } else { pick(*c, v) };
+            }
+        }
+        out.iter().fold(0u32, |acc, c| (acc << 8) | *c as u32)
+    };
+    let src = data.to_vec();
+    for y in 0..height {
+        for x in 0..width {
+            let (x0, x1) = (x.saturating_sub(radius), (x + radius).min(width - 1));
+            data[y * width + x] = reduce(&mut src[y * width + x0..=y * width + x1].iter().copied());
+        }
+    }
+    let src = data.to_vec();
+    for y in 0..height {
+        let (y0, y1) = (y.saturating_sub(radius), (y + radius).min(height - 1));
+        for x in 0..width {
+            data[y * width + x] = reduce(&mut (y0..=y1).map(|yy| src[yy * width + x]));
+        }
+    }
+}
+
+// Convolves with a kw x kh kernel centered on each pixel, clamping reads at
+// the edges. All four channels are convolved in premultiplied space, so transparent
+// neighbours don't bleed their colour into the result. With `preserve_alpha`
+// only the un-premultiplied colour channels are filtered and alpha is kept,
+// which kernels summing to zero need to avoid clearing the alpha channel.
+fn convolve(target: &mut DrawTarget, kw: usize, kh: usize, kernel: &[f32], divisor: f32, offset: f32, preserve_alpha: bool) {
+    let width = target.width() as usize;
+    let height = target.height() as usize;
+    let data = target.get_data_mut();
+    let src: Vec<[u8; 4]> = if preserve_alpha {
+        data.iter().map(|p| unpremultiply(*p)).collect()
+    } else {
+        data.iter().map(|p| p.to_be_bytes()).collect()
+    };
+    let channel = |v: f32| v.round().max(0.0).min(255.0);
+    for y in 0..height {
+        for x in 0..width {
+            let mut acc = [0f32; 4];
+            for ky in 0..kh {
+                let sy = (y + ky).saturating_sub(kh / 2).min(height - 1);
+                for kx in 0..kw {
+                    let sx = (x + kx).saturating_sub(kw / 2).min(width - 1);
+                    let weight = kernel[ky * kw + kx];
+                    let pixel = &src[sy * width + sx];
+                    for (c, a) in acc.iter_mut().enumerate() {
+                        *a += pixel[c] as f32 * weight;
+                    }
+                }
+            }
+            data[y * width + x] = if preserve_alpha {
+                let mut out = src[y * width + x];
+                for (c, a) in acc.iter().enumerate().skip(1) {
+                    out[c] = channel(a / divisor + offset) as u8;
+                }
+                premultiply(out)
+            } else {
+                // The offset is scaled by alpha and colours clamped to it so
+                // the result stays a valid premultiplied pixel.
+                let alpha = channel(acc[0] / divisor);
+                let mut out = [alpha as u8; 4];
+                for (c, a) in acc.iter().enumerate().skip(1) {
+                    out[c] = channel(a / divisor + offset * alpha / 255.0).min(alpha) as u8;
+                }
+                u32::from_be_bytes(out)
+            };
+        }
+    }
+}
+
+fn op_new_draw_target(
+    _interface: &mut dyn Interface, 
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let width = get_arg_i32(_args, 1)?;
+        let height = get_arg_i32(_args, 2)?;
+        TARGETS.with(|map| {
+            let mut targets = map.borrow_mut();
+            if targets.contains_key(&id) {
+                Err(PluginError::TargetExists(id))
+            } else {
+                let dt = new_target(width, height)?;
+                targets.insert(id, dt);
+                Ok(ok_response())
+            }
+        })
+    })
+}
+
+fn op_new_draw_target_auto(
     _interface: &mut dyn Interface,
     _args: &mut [ZeroCopyBuf],
 ) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let rc = get_arg_u8(_args, 1).unwrap();
-    let m11 = get_arg_f32(_args, 2).unwrap();
-    let m21 = get_arg_f32(_args, 3).unwrap();
-    let m31 = get_arg_f32(_args, 4).unwrap();
-    let m12 = get_arg_f32(_args, 5).unwrap();
-    let m22 = get_arg_f32(_args, 6).unwrap();
-    let m32 = get_arg_f32(_args, 7).unwrap();
+    run_op(|| {
+        let width = get_arg_i32(_args, 0)?;
+        let height = get_arg_i32(_args, 1)?;
+        let dt = new_target(width, height)?;
+        Ok(value_response(insert_target_auto(dt)))
+    })
+}
+
+fn insert_target_auto(dt: DrawTarget) -> u32 {
     TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
-            let mut res= b"0";
+        let mut targets = map.borrow_mut();
+        // Skip IDs that were claimed explicitly through op_new_draw_target.
+        let mut id = NEXT_TARGET_ID.fetch_add(1, Ordering::SeqCst);
+        while targets.contains_key(&id) {
+            id = NEXT_TARGET_ID.fetch_add(1, Ordering::SeqCst);
+        }
+        targets.insert(id, dt);
+        id
+    })
+}
+
+fn op_dt_destroy(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id: u32 = get_arg_u32(_args, 0)?;
+        TARGETS.with(|map| {
+            map.borrow_mut().remove(&id).ok_or(PluginError::TargetNotFound(id))?;
+            STACK_DEPTHS.with(|depths| depths.borrow_mut().remove(&id));
+            METADATA.with(|metadata| metadata.borrow_mut().remove(&id));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_get_data(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id: u32 = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            Ok(Op::Sync(target.get_data_u8().to_vec().into_boxed_slice()))
+        })
+    })
+}
+
+fn op_dt_width(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id: u32 = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            Ok(value_response(target.width()))
+        })
+    })
+}
+
+fn op_dt_height(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id: u32 = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            Ok(value_response(target.height()))
+        })
+    })
+}
+
+fn op_dt_encode(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id: u32 = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            let path = temp_dir().join(format!("{}.png", uuid::Uuid::new_v4()));
+            target.write_png(&path).map_err(|e| PluginError::Image(format!("{:?}", e)))?;
+            let mut buf = Vec::<u8>::new();
+            let mut file = std::fs::File::open(&path)?;
+            file.read_to_end(&mut buf)?;
+            std::fs::remove_file(&path)?;
+            Ok(Op::Sync(buf.into_boxed_slice()))
+        })
+    })
+}
+
+fn op_dt_write_png(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id: u32 = get_arg_u32(_args, 0)?;
+        let path = get_arg_str(_args, 1)?;
+        with_target(id, |target| {
+            target.write_png(path).map_err(|e| PluginError::Image(format!("{:?}", e)))?;
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_fill_rect(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 1)?;
+        let y = get_arg_f32(_args, 2)?;
+        let w = get_arg_f32(_args, 3)?;
+        let h = get_arg_f32(_args, 4)?;
+        let src = get_arg_src(_args, 5, 6)?;
+        with_target(id, |target| {
+            src.draw(target, |target, src| target.fill_rect(x, y, w, h, src, &DrawOptions::new()));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_clear(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let a = get_arg_u8(_args, 1)?;
+        let r = get_arg_u8(_args, 2)?;
+        let g = get_arg_u8(_args, 3)?;
+        let b = get_arg_u8(_args, 4)?;
+        with_target(id, |target| {
+            target.clear(SolidSource::from(Color::new(a, r, g, b)));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_fill(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let path = get_arg_path(_args, 1)?;
+        let src = get_arg_src(_args, 2, 3)?;
+        with_target(id, |target| {
+            src.draw(target, |target, src| target.fill(&path, src, &DrawOptions::new()));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_stroke(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let path = get_arg_path(_args, 1)?;
+        let stroke = stroke_style_from_json(get_arg_json(_args, 3)?);
+        let src = get_arg_src(_args, 2, 4)?;
+        with_target(id, |target| {
+            src.draw(target, |target, src| target.stroke(&path, src, &stroke, &DrawOptions::new()));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_image_at(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        with_target(id, |target| {
+            let img = get_arg_img(_args, 1)?;
+            target.draw_image_at(x, y, &Image {
+                width: img.width as i32,
+                height: img.height as i32,
+                data: &*img.data
+            }, &DrawOptions::new());
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_image_with_size_at(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let w = get_arg_f32(_args, 4)?;
+        let h = get_arg_f32(_args, 5)?;
+        with_target(id, |target| {
+            let img = get_arg_img(_args, 1)?;
+            target.draw_image_with_size_at(x, y, w, h, &Image {
+                width: img.width as i32,
+                height: img.height as i32,
+                data: &*img.data
+            }, &DrawOptions::new());
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_set_transform(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let rc = get_arg_u8(_args, 1)?;
+        let m11 = get_arg_f32(_args, 2)?;
+        let m21 = get_arg_f32(_args, 3)?;
+        let m31 = get_arg_f32(_args, 4)?;
+        let m12 = get_arg_f32(_args, 5)?;
+        let m22 = get_arg_f32(_args, 6)?;
+        let m32 = get_arg_f32(_args, 7)?;
+        with_target(id, |target| {
             if rc == 0 { target.set_transform(&Transform::column_major(m11, m21, m31, m12, m22, m32)); }
             else if rc == 1 { target.set_transform(&Transform::row_major(m11, m21, m31, m12, m22, m32)); }
             else if rc == 2 { target.set_transform(&Transform::create_scale(m11, m21)); }
@@ -653,9 +1603,9 @@ fn op_dt_set_transform(
             else if rc == 4 {
                 let angle = if m11 == 0.0 { euclid::Angle::degrees(m21) } else { euclid::Angle::radians(m21) };
                 target.set_transform(&Transform::create_rotation(angle));
-            } else { res = b"1"; }
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+            } else { return Err(PluginError::Invalid(format!("unknown transform kind {}", rc))); }
+            Ok(ok_response())
+        })
     })
 }
 
@@ -663,17 +1613,17 @@ fn op_dt_push_clip_rect(
     _interface: &mut dyn Interface,
     _args: &mut [ZeroCopyBuf],
 ) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let x1 = get_arg_i32(_args, 1).unwrap();
-    let y1 = get_arg_i32(_args, 2).unwrap();
-    let x2 = get_arg_i32(_args, 3).unwrap();
-    let y2 = get_arg_i32(_args, 4).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x1 = get_arg_i32(_args, 1)?;
+        let y1 = get_arg_i32(_args, 2)?;
+        let x2 = get_arg_i32(_args, 3)?;
+        let y2 = get_arg_i32(_args, 4)?;
+        with_target(id, |target| {
             target.push_clip_rect(IntRect::new(Point2D::<i32, UnknownUnit>::new(x1, y1), Point2D::<i32, UnknownUnit>::new(x2, y2)));
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+            with_depths(id, |depths| depths.0 += 1);
+            Ok(ok_response())
+        })
     })
 }
 
@@ -681,14 +1631,14 @@ fn op_dt_push_clip(
     _interface: &mut dyn Interface,
     _args: &mut [ZeroCopyBuf],
 ) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let path = get_arg_path(_args, 1).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let path = get_arg_path(_args, 1)?;
+        with_target(id, |target| {
             target.push_clip(&path);
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+            with_depths(id, |depths| depths.0 += 1);
+            Ok(ok_response())
+        })
     })
 }
 
@@ -696,13 +1646,13 @@ fn op_dt_pop_clip(
     _interface: &mut dyn Interface,
     _args: &mut [ZeroCopyBuf],
 ) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            pop_clip_depth(id)?;
             target.pop_clip();
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+            Ok(ok_response())
+        })
     })
 }
 
@@ -710,13 +1660,13 @@ fn op_dt_pop_layer(
     _interface: &mut dyn Interface,
     _args: &mut [ZeroCopyBuf],
 ) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            pop_layer_depth(id)?;
             target.pop_layer();
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+            Ok(ok_response())
+        })
     })
 }
 
@@ -724,14 +1674,14 @@ fn op_dt_push_layer(
     _interface: &mut dyn Interface,
     _args: &mut [ZeroCopyBuf],
 ) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let opacity = get_arg_f32(_args, 1).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let opacity = get_arg_f32(_args, 1)?;
+        with_target(id, |target| {
             target.push_layer(opacity);
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+            with_depths(id, |depths| depths.1 += 1);
+            Ok(ok_response())
+        })
     })
 }
 
@@ -739,14 +1689,3969 @@ fn op_dt_push_layer_with_blend(
     _interface: &mut dyn Interface,
     _args: &mut [ZeroCopyBuf],
 ) -> Op {
-    let id = get_arg_u32(_args, 0).unwrap();
-    let opacity = get_arg_f32(_args, 1).unwrap();
-    let blend: JsonBlendMode = deno_core::serde_json::from_str(get_arg_str(_args, 2).unwrap()).unwrap();
-    TARGETS.with(|map| {
-        if let Some(target) = map.borrow_mut().get_mut(&id) {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let opacity = get_arg_f32(_args, 1)?;
+        let blend: JsonBlendMode = get_arg_json(_args, 2)?;
+        with_target(id, |target| {
             target.push_layer_with_blend(opacity, blend_from_json(blend));
-            let res= b"0";
-            Op::Sync(res.to_vec().into_boxed_slice())
-        } else { let res= b"1"; Op::Sync(res.to_vec().into_boxed_slice()) }
+            with_depths(id, |depths| depths.1 += 1);
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_posterize(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let levels = get_arg_u8(_args, 1)?;
+        if levels < 2 {
+            return Err(PluginError::Invalid("levels must be at least 2".to_string()));
+        }
+        let steps = (levels - 1) as f32;
+        let quantize = |c: u8| (((c as f32) * steps / 255.0).round() * 255.0 / steps).round() as u8;
+        with_target(id, |target| {
+            for pixel in target.get_data_mut().iter_mut() {
+                let [a, r, g, b] = unpremultiply(*pixel);
+                *pixel = premultiply([a, quantize(r), quantize(g), quantize(b)]);
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_flip_horizontal(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            let width = target.width() as usize;
+            for row in target.get_data_mut().chunks_mut(width) {
+                row.reverse();
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_flip_vertical(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            let width = target.width() as usize;
+            let height = target.height() as usize;
+            let data = target.get_data_mut();
+            for y in 0..height / 2 {
+                let (top, bottom) = data.split_at_mut((height - 1 - y) * width);
+                top[y * width..(y + 1) * width].swap_with_slice(&mut bottom[..width]);
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_rotate_90(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            *target = rotate_quarter(target, true);
+            // The rotated target starts with empty clip and layer stacks.
+            with_depths(id, |depths| *depths = (0, 0));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_rotate_180(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            target.get_data_mut().reverse();
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_rotate_270(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            *target = rotate_quarter(target, false);
+            // The rotated target starts with empty clip and layer stacks.
+            with_depths(id, |depths| *depths = (0, 0));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_pixelate(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let block_size = get_arg_u32(_args, 1)? as usize;
+        if block_size < 1 {
+            return Err(PluginError::Invalid("block_size must be at least 1".to_string()));
+        }
+        with_target(id, |target| {
+            let width = target.width() as usize;
+            let height = target.height() as usize;
+            let data = target.get_data_mut();
+            for by in (0..height).step_by(block_size) {
+                for bx in (0..width).step_by(block_size) {
+                    let y_end = (by + block_size).min(height);
+                    let x_end = (bx + block_size).min(width);
+                    // Premultiplied channels can be averaged directly.
+                    let mut sum = [0u64; 4];
+                    for y in by..y_end {
+                        for pixel in &data[y * width + bx..y * width + x_end] {
+                            for (i, s) in sum.iter_mut().enumerate() {
+                                *s += ((pixel >> (24 - i * 8)) & 0xff) as u64;
+                            }
+                        }
+                    }
+                    let count = ((y_end - by) * (x_end - bx)) as u64;
+                    let avg = sum.iter().enumerate().fold(0u32, |acc, (i, s)| {
+                        acc | ((((s + count / 2) / count) as u32) << (24 - i * 8))
+                    });
+                    for y in by..y_end {
+                        for pixel in &mut data[y * width + bx..y * width + x_end] {
+                            *pixel = avg;
+                        }
+                    }
+                }
+            }
+            Ok(ok_response())
+        })
     })
 }
+
+fn op_dt_sharpen(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let amount = get_arg_f32(_args, 1)?;
+        with_target(id, |target| {
+            let width = target.width() as usize;
+            let height = target.height() as usize;
+            let data = target.get_data_mut();
+            let src: Vec<[u8; 4]> = data.iter().map(|p| unpremultiply(*p)).collect();
+            let at = |x: usize, y: usize| &src[y * width + x];
+            for y in 0..height {
+                for x in 0..width {
+                    let neighbors = [
+                        at(x, y.saturating_sub(1)),
+                        at(x, (y + 1).min(height - 1)),
+                        at(x.saturating_sub(1), y),
+                        at((x + 1).min(width - 1), y),
+                    ];
+                    let center = at(x, y);
+                    let mut out = *center;
+                    // The kernel only has the four edge neighbours, so the
+                    // centre weight is 1 + 4 * amount rather than the
+                    // 1 + 8 * amount of an eight-neighbour kernel. That keeps
+                    // the weights summing to 1 and flat areas unchanged.
+                    for c in 1..4 {
+                        let edges: f32 = neighbors.iter().map(|n| n[c] as f32).sum();
+                        let v = center[c] as f32 * (1.0 + 4.0 * amount) - edges * amount;
+                        out[c] = v.round().max(0.0).min(255.0) as u8;
+                    }
+                    data[y * width + x] = premultiply(out);
+                }
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_erode(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let radius = get_arg_u32(_args, 1)?;
+        with_target(id, |target| {
+            morphology(target, radius as usize, std::cmp::min);
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_dilate(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let radius = get_arg_u32(_args, 1)?;
+        with_target(id, |target| {
+            morphology(target, radius as usize, std::cmp::max);
+            Ok(ok_response())
+        })
+    })
+}
+
+// Cost is O(width * height * (2 * radius + 1)^2); keep radius <= 3 for
+// interactive use.
+fn op_dt_median_filter(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let radius = get_arg_u32(_args, 1)? as usize;
+        with_target(id, |target| {
+            let width = target.width() as usize;
+            let height = target.height() as usize;
+            let data = target.get_data_mut();
+            let src: Vec<[u8; 4]> = data.iter().map(|p| unpremultiply(*p)).collect();
+            let mut window = Vec::<u8>::with_capacity((2 * radius + 1) * (2 * radius + 1));
+            for y in 0..height {
+                let (y0, y1) = (y.saturating_sub(radius), (y + radius).min(height - 1));
+                for x in 0..width {
+                    let (x0, x1) = (x.saturating_sub(radius), (x + radius).min(width - 1));
+                    let mut out = src[y * width + x];
+                    for c in 1..4 {
+                        window.clear();
+                        for yy in y0..=y1 {
+                            window.extend(src[yy * width + x0..=yy * width + x1].iter().map(|p| p[c]));
+                        }
+                        window.sort_unstable();
+                        out[c] = window[window.len() / 2];
+                    }
+                    data[y * width + x] = premultiply(out);
+                }
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_tint(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let r = get_arg_u8(_args, 1)?;
+        let g = get_arg_u8(_args, 2)?;
+        let b = get_arg_u8(_args, 3)?;
+        let mul = |c: u8, t: u8| ((c as u32 * t as u32 + 127) / 255) as u8;
+        with_target(id, |target| {
+            for pixel in target.get_data_mut().iter_mut() {
+                let [pa, pr, pg, pb] = unpremultiply(*pixel);
+                *pixel = premultiply([pa, mul(pr, r), mul(pg, g), mul(pb, b)]);
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_opacity(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let factor = get_arg_f32(_args, 1)?;
+        if !(0.0..=1.0).contains(&factor) {
+            return Err(PluginError::Invalid("opacity factor must be within [0, 1]".to_string()));
+        }
+        with_target(id, |target| {
+            // Premultiplied color scales together with alpha.
+            for pixel in target.get_data_u8_mut().iter_mut() {
+                *pixel = (*pixel as f32 * factor).round() as u8;
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_apply_lut(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let lut = get_arg_buf(_args, 1)?.to_vec();
+        if lut.len() != 768 {
+            return Err(PluginError::Invalid("lut must be exactly 768 bytes".to_string()));
+        }
+        with_target(id, |target| {
+            for pixel in target.get_data_mut().iter_mut() {
+                let [a, r, g, b] = unpremultiply(*pixel);
+                *pixel = premultiply([a, lut[r as usize], lut[256 + g as usize], lut[512 + b as usize]]);
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_convolve(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let kw = get_arg_u32(_args, 1)? as usize;
+        let kh = get_arg_u32(_args, 2)? as usize;
+        let divisor = get_arg_f32(_args, 3)?;
+        let offset = get_arg_f32(_args, 4)?;
+        // Optional, so the op still takes the six-argument form.
+        let preserve_alpha = if _args.len() > 6 {
+            match get_arg_u8(_args, 6)? {
+                0 => false,
+                1 => true,
+                _ => return Err(PluginError::InvalidArg(6, "expected 0 or 1".to_string())),
+            }
+        } else {
+            false
+        };
+        let buf = get_arg_buf(_args, 5)?;
+        if kw == 0 || kh == 0 || buf.len() != kw * kh * 4 || divisor == 0.0 {
+            return Err(PluginError::Invalid("kernel must hold kw * kh floats and divisor must be non-zero".to_string()));
+        }
+        let kernel: Vec<f32> = buf
+            .chunks_exact(4)
+            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        with_target(id, |target| {
+            convolve(target, kw, kh, &kernel, divisor, offset, preserve_alpha);
+            Ok(ok_response())
+        })
+    })
+}
+
+// Adjustments are added to un-premultiplied channel values. Midtones are
+// weighted by a bell curve around mid-gray, the remainder going to the nearer
+// of the shadow and highlight adjustments.
+fn op_dt_color_balance(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let shadows: [f32; 3] = get_arg_json(_args, 1)?;
+        let midtones: [f32; 3] = get_arg_json(_args, 2)?;
+        let highlights: [f32; 3] = get_arg_json(_args, 3)?;
+        with_target(id, |target| {
+            for pixel in target.get_data_mut().iter_mut() {
+                let [a, r, g, b] = unpremultiply(*pixel);
+                let lum = 0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32;
+                let adjust: [f32; 3] = if lum < 85.0 {
+                    shadows
+                } else if lum > 170.0 {
+                    highlights
+                } else {
+                    let w = (-(lum - 127.5).powi(2) / (2.0 * 21.25 * 21.25)).exp();
+                    let side = if lum < 127.5 { shadows } else { highlights };
+                    let mix = |c: usize| midtones[c] * w + side[c] * (1.0 - w);
+                    [mix(0), mix(1), mix(2)]
+                };
+                let apply = |c: u8, d: f32| (c as f32 + d).round().max(0.0).min(255.0) as u8;
+                *pixel = premultiply([a, apply(r, adjust[0]), apply(g, adjust[1]), apply(b, adjust[2])]);
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_list(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        TARGETS.with(|map| {
+            let mut ids: Vec<u32> = map.borrow().keys().cloned().collect();
+            ids.sort();
+            Ok(value_response(ids))
+        })
+    })
+}
+
+fn op_dt_count(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        TARGETS.with(|map| Ok(value_response(map.borrow().len())))
+    })
+}
+
+fn op_dt_push_clip_rect_float(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x1 = get_arg_f32(_args, 1)?;
+        let y1 = get_arg_f32(_args, 2)?;
+        let x2 = get_arg_f32(_args, 3)?;
+        let y2 = get_arg_f32(_args, 4)?;
+        let mut pb = PathBuilder::new();
+        pb.move_to(x1, y1);
+        pb.line_to(x2, y1);
+        pb.line_to(x2, y2);
+        pb.line_to(x1, y2);
+        pb.close();
+        let path = pb.finish();
+        with_target(id, |target| {
+            target.push_clip(&path);
+            with_depths(id, |depths| depths.0 += 1);
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_clip_depth(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |_| Ok(value_response(with_depths(id, |depths| depths.0))))
+    })
+}
+
+fn op_dt_layer_depth(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |_| Ok(value_response(with_depths(id, |depths| depths.1))))
+    })
+}
+
+fn op_dt_pop_all_clips(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            let count = with_depths(id, |depths| std::mem::replace(&mut depths.0, 0));
+            for _ in 0..count {
+                target.pop_clip();
+            }
+            Ok(value_response(count))
+        })
+    })
+}
+
+fn op_dt_pop_all_layers(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            let count = with_depths(id, |depths| std::mem::replace(&mut depths.1, 0));
+            for _ in 0..count {
+                target.pop_layer();
+            }
+            Ok(value_response(count))
+        })
+    })
+}
+
+fn op_gradient_store(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let json: JsonSource = get_arg_json(_args, 1)?;
+        match json.src_type {
+            JsonSourceType::Solid | JsonSourceType::Pattern | JsonSourceType::Checkerboard => {
+                return Err(PluginError::Invalid("only gradient sources can be stored".to_string()));
+            }
+            _ => {}
+        }
+        let src = source_from_json(json, None)?;
+        GRADIENTS.with(|map| map.borrow_mut().insert(id, src));
+        Ok(ok_response())
+    })
+}
+
+fn op_dt_fill_with_gradient(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let path = get_arg_path(_args, 1)?;
+        let gradient_id = get_arg_u32(_args, 2)?;
+        with_gradient(gradient_id, |src| {
+            with_target(id, |target| {
+                src.draw(target, |target, src| target.fill(&path, src, &DrawOptions::new()));
+                Ok(ok_response())
+            })
+        })
+    })
+}
+
+fn op_dt_stroke_with_gradient(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let path = get_arg_path(_args, 1)?;
+        let gradient_id = get_arg_u32(_args, 2)?;
+        let stroke = stroke_style_from_json(get_arg_json(_args, 3)?);
+        with_gradient(gradient_id, |src| {
+            with_target(id, |target| {
+                src.draw(target, |target, src| target.stroke(&path, src, &stroke, &DrawOptions::new()));
+                Ok(ok_response())
+            })
+        })
+    })
+}
+
+fn op_dt_fill_rect_batch(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let batch: Vec<JsonFillRect> = get_arg_json(_args, 1)?;
+        let mut rects = Vec::with_capacity(batch.len());
+        for rect in batch {
+            rects.push((rect.x, rect.y, rect.w, rect.h, source_from_json(rect.src, None)?));
+        }
+        with_target(id, |target| {
+            for (x, y, w, h, src) in &rects {
+                src.draw(target, |target, src| target.fill_rect(*x, *y, *w, *h, src, &DrawOptions::new()));
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_stroke_path_batch(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let batch: Vec<JsonStrokeCommand> = get_arg_json(_args, 1)?;
+        let mut strokes = Vec::with_capacity(batch.len());
+        for cmd in batch {
+            strokes.push((
+                path_from_json(cmd.path)?,
+                source_from_json(cmd.src, None)?,
+                stroke_style_from_json(cmd.stroke),
+                cmd.options.map(draw_options_from_json).unwrap_or_else(DrawOptions::new),
+            ));
+        }
+        with_target(id, |target| {
+            for (path, src, stroke, options) in &strokes {
+                src.draw(target, |target, src| target.stroke(path, src, stroke, options));
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_fill_path_batch(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let batch: Vec<JsonFillCommand> = get_arg_json(_args, 1)?;
+        let mut fills = Vec::with_capacity(batch.len());
+        for cmd in batch {
+            let mut path = path_from_json(cmd.path)?;
+            if let Some(rule) = cmd.fill_rule {
+                path.winding = winding_from_json(rule);
+            }
+            fills.push((
+                path,
+                source_from_json(cmd.src, None)?,
+                cmd.options.map(draw_options_from_json).unwrap_or_else(DrawOptions::new),
+            ));
+        }
+        with_target(id, |target| {
+            for (path, src, options) in &fills {
+                src.draw(target, |target, src| target.fill(path, src, options));
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+enum Command {
+    FillRect(f32, f32, f32, f32, PaintSource),
+    Fill(Path, PaintSource, DrawOptions),
+    Stroke(Path, PaintSource, StrokeStyle, DrawOptions),
+    DrawImage(JsonImage, f32, f32, Option<(f32, f32)>),
+    PushClip(Path),
+    PopClip,
+    PushLayer(f32, BlendMode),
+    PopLayer,
+    SetTransform(Transform),
+    Clear(SolidSource),
+}
+
+fn command_from_json(json: JsonCommand, args: &mut [ZeroCopyBuf]) -> PluginResult<Command> {
+    Ok(match json {
+        JsonCommand::FillRect(rect) => {
+            Command::FillRect(rect.x, rect.y, rect.w, rect.h, source_from_json(rect.src, None)?)
+        }
+        JsonCommand::Fill(cmd) => {
+            let mut path = path_from_json(cmd.path)?;
+            if let Some(rule) = cmd.fill_rule {
+                path.winding = winding_from_json(rule);
+            }
+            let options = cmd.options.map(draw_options_from_json).unwrap_or_else(DrawOptions::new);
+            Command::Fill(path, source_from_json(cmd.src, None)?, options)
+        }
+        JsonCommand::Stroke(cmd) => {
+            let options = cmd.options.map(draw_options_from_json).unwrap_or_else(DrawOptions::new);
+            Command::Stroke(path_from_json(cmd.path)?, source_from_json(cmd.src, None)?, stroke_style_from_json(cmd.stroke), options)
+        }
+        JsonCommand::DrawImage { image, x, y, w, h } => {
+            let size = match (w, h) {
+                (Some(w), Some(h)) => Some((w, h)),
+                (None, None) => None,
+                _ => return Err(PluginError::Invalid("draw_image needs both w and h or neither".to_string())),
+            };
+            Command::DrawImage(get_arg_img(args, 2 + image)?, x, y, size)
+        }
+        JsonCommand::PushClip { path } => Command::PushClip(path_from_json(path)?),
+        JsonCommand::PushClipRect { x1, y1, x2, y2 } => {
+            let mut pb = PathBuilder::new();
+            pb.move_to(x1, y1);
+            pb.line_to(x2, y1);
+            pb.line_to(x2, y2);
+            pb.line_to(x1, y2);
+            pb.close();
+            Command::PushClip(pb.finish())
+        }
+        JsonCommand::PopClip => Command::PopClip,
+        JsonCommand::PushLayer { opacity, blend_mode } => {
+            Command::PushLayer(opacity, blend_mode.map(blend_from_json).unwrap_or(BlendMode::SrcOver))
+        }
+        JsonCommand::PopLayer => Command::PopLayer,
+        JsonCommand::SetTransform { transform } => Command::SetTransform(transform_from_json(transform)),
+        JsonCommand::Clear { color } => Command::Clear(SolidSource::from(color_from_json(color))),
+    })
+}
+
+fn op_dt_execute_command_list(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let json: Vec<JsonCommand> = get_arg_json(_args, 1)?;
+        let mut commands = Vec::with_capacity(json.len());
+        for cmd in json {
+            commands.push(command_from_json(cmd, _args)?);
+        }
+        with_target(id, |target| {
+            // Check that the list never pops more than has been pushed
+            // before drawing anything.
+            let (mut clips, mut layers) = with_depths(id, |depths| *depths);
+            for cmd in &commands {
+                match cmd {
+                    Command::PushClip(_) => clips += 1,
+                    Command::PushLayer(..) => layers += 1,
+                    Command::PopClip if clips == 0 => return Err(PluginError::Invalid("no clip to pop".to_string())),
+                    Command::PopClip => clips -= 1,
+                    Command::PopLayer if layers == 0 => return Err(PluginError::Invalid("no layer to pop".to_string())),
+                    Command::PopLayer => layers -= 1,
+                    _ => {}
+                }
+            }
+            for cmd in &commands {
+                match cmd {
+                    Command::FillRect(x, y, w, h, src) => {
+                        src.draw(target, |target, src| target.fill_rect(*x, *y, *w, *h, src, &DrawOptions::new()));
+                    }
+                    Command::Fill(path, src, options) => {
+                        src.draw(target, |target, src| target.fill(path, src, options));
+                    }
+                    Command::Stroke(path, src, stroke, options) => {
+                        src.draw(target, |target, src| target.stroke(path, src, stroke, options));
+                    }
+                    Command::DrawImage(img, x, y, size) => {
+                        let image = Image { width: img.width as i32, height: img.height as i32, data: &*img.data };
+                        match size {
+                            Some((w, h)) => target.draw_image_with_size_at(*x, *y, *w, *h, &image, &DrawOptions::new()),
+                            None => target.draw_image_at(*x, *y, &image, &DrawOptions::new()),
+                        }
+                    }
+                    Command::PushClip(path) => target.push_clip(path),
+                    Command::PopClip => target.pop_clip(),
+                    Command::PushLayer(opacity, blend) => target.push_layer_with_blend(*opacity, *blend),
+                    Command::PopLayer => target.pop_layer(),
+                    Command::SetTransform(transform) => target.set_transform(transform),
+                    Command::Clear(color) => target.clear(*color),
+                }
+            }
+            with_depths(id, |depths| *depths = (clips, layers));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_image_decode(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let img = get_arg_img(_args, 1)?;
+        let size = [img.width, img.height];
+        IMAGES.with(|map| map.borrow_mut().insert(id, img));
+        Ok(value_response(size))
+    })
+}
+
+fn op_image_destroy(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        IMAGES.with(|map| {
+            map.borrow_mut().remove(&id).ok_or(PluginError::ImageNotFound(id))?;
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_cached_image_at(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let image_id = get_arg_u32(_args, 1)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        with_image(image_id, |img| {
+            with_target(id, |target| {
+                target.draw_image_at(x, y, &Image {
+                    width: img.width as i32,
+                    height: img.height as i32,
+                    data: &*img.data
+                }, &DrawOptions::new());
+                Ok(ok_response())
+            })
+        })
+    })
+}
+
+fn op_dt_draw_cached_image_with_size_at(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let image_id = get_arg_u32(_args, 1)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let w = get_arg_f32(_args, 4)?;
+        let h = get_arg_f32(_args, 5)?;
+        with_image(image_id, |img| {
+            with_target(id, |target| {
+                target.draw_image_with_size_at(x, y, w, h, &Image {
+                    width: img.width as i32,
+                    height: img.height as i32,
+                    data: &*img.data
+                }, &DrawOptions::new());
+                Ok(ok_response())
+            })
+        })
+    })
+}
+
+fn op_dt_draw_image_with_opacity(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let opacity = get_arg_f32(_args, 4)?;
+        if !(0.0..=1.0).contains(&opacity) {
+            return Err(PluginError::Invalid("opacity must be within [0, 1]".to_string()));
+        }
+        let img = get_arg_img(_args, 1)?;
+        with_target(id, |target| {
+            // Same result as wrapping the draw in push_layer/pop_layer, without
+            // allocating a full-size layer.
+            let mut options = DrawOptions::new();
+            options.alpha = opacity;
+            target.draw_image_at(x, y, &Image {
+                width: img.width as i32,
+                height: img.height as i32,
+                data: &*img.data
+            }, &options);
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_fill_arc(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let cx = get_arg_f32(_args, 1)?;
+        let cy = get_arg_f32(_args, 2)?;
+        let r = get_arg_f32(_args, 3)?;
+        let start_angle = get_arg_f32(_args, 4)?;
+        let sweep_angle = get_arg_f32(_args, 5)?;
+        let include_center = match get_arg_u8(_args, 6)? {
+            0 => false,
+            1 => true,
+            _ => return Err(PluginError::InvalidArg(6, "expected 0 or 1".to_string())),
+        };
+        let src = get_arg_src(_args, 7, 8)?;
+        let mut pb = PathBuilder::new();
+        // arc() starts with a line to the arc's first point, so a sector
+        // starts at the centre and a chord at the arc itself.
+        if include_center {
+            pb.move_to(cx, cy);
+        } else {
+            pb.move_to(cx + r * start_angle.cos(), cy + r * start_angle.sin());
+        }
+        pb.arc(cx, cy, r, start_angle, sweep_angle);
+        pb.close();
+        let path = pb.finish();
+        with_target(id, |target| {
+            src.draw(target, |target, src| target.fill(&path, src, &DrawOptions::new()));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_stroke_arc(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let cx = get_arg_f32(_args, 1)?;
+        let cy = get_arg_f32(_args, 2)?;
+        let r = get_arg_f32(_args, 3)?;
+        let start_angle = get_arg_f32(_args, 4)?;
+        let sweep_angle = get_arg_f32(_args, 5)?;
+        let src = get_arg_src(_args, 6, 8)?;
+        let stroke = stroke_style_from_json(get_arg_json(_args, 7)?);
+        let mut pb = PathBuilder::new();
+        pb.move_to(cx + r * start_angle.cos(), cy + r * start_angle.sin());
+        pb.arc(cx, cy, r, start_angle, sweep_angle);
+        let path = pb.finish();
+        with_target(id, |target| {
+            src.draw(target, |target, src| target.stroke(&path, src, &stroke, &DrawOptions::new()));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_fill_donut(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let cx = get_arg_f32(_args, 1)?;
+        let cy = get_arg_f32(_args, 2)?;
+        let outer_r = get_arg_f32(_args, 3)?;
+        let inner_r = get_arg_f32(_args, 4)?;
+        if inner_r < 0. || inner_r >= outer_r {
+            return Err(PluginError::Invalid("inner radius must be within [0, outer radius)".to_string()));
+        }
+        let src = get_arg_src(_args, 5, 6)?;
+        let tau = 2. * std::f32::consts::PI;
+        let mut pb = PathBuilder::new();
+        pb.move_to(cx + outer_r, cy);
+        pb.arc(cx, cy, outer_r, 0., tau);
+        pb.close();
+        pb.move_to(cx + inner_r, cy);
+        pb.arc(cx, cy, inner_r, 0., -tau);
+        pb.close();
+        let mut path = pb.finish();
+        path.winding = Winding::EvenOdd;
+        with_target(id, |target| {
+            src.draw(target, |target, src| target.fill(&path, src, &DrawOptions::new()));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_regular_polygon(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let cx = get_arg_f32(_args, 1)?;
+        let cy = get_arg_f32(_args, 2)?;
+        let r = get_arg_f32(_args, 3)?;
+        let sides = get_arg_u32(_args, 4)?;
+        let rotation = get_arg_f32(_args, 5)?;
+        if sides < 3 {
+            return Err(PluginError::Invalid("a polygon needs at least 3 sides".to_string()));
+        }
+        let src = get_arg_src(_args, 6, 9)?;
+        let mode = get_arg_paint_mode(_args, 7, 8)?;
+        let path = radial_polygon(cx, cy, sides, rotation, |_| r);
+        with_target(id, |target| {
+            mode.paint(target, &path, &src);
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_star_polygon(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let cx = get_arg_f32(_args, 1)?;
+        let cy = get_arg_f32(_args, 2)?;
+        let r_outer = get_arg_f32(_args, 3)?;
+        let r_inner = get_arg_f32(_args, 4)?;
+        let points = get_arg_u32(_args, 5)?;
+        let rotation = get_arg_f32(_args, 6)?;
+        if points < 2 {
+            return Err(PluginError::Invalid("a star needs at least 2 points".to_string()));
+        }
+        let src = get_arg_src(_args, 7, 10)?;
+        let mode = get_arg_paint_mode(_args, 8, 9)?;
+        let path = radial_polygon(cx, cy, points * 2, rotation, |i| if i % 2 == 0 { r_outer } else { r_inner });
+        with_target(id, |target| {
+            mode.paint(target, &path, &src);
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_arrow(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x1 = get_arg_f32(_args, 1)?;
+        let y1 = get_arg_f32(_args, 2)?;
+        let x2 = get_arg_f32(_args, 3)?;
+        let y2 = get_arg_f32(_args, 4)?;
+        let head_size = get_arg_f32(_args, 5)?;
+        let src = get_arg_src(_args, 6, 8)?;
+        let stroke = stroke_style_from_json(get_arg_json(_args, 7)?);
+        let len = (x2 - x1).hypot(y2 - y1);
+        if len == 0. {
+            return Err(PluginError::Invalid("arrow start and end must differ".to_string()));
+        }
+        if head_size < 0. {
+            return Err(PluginError::Invalid("arrowhead size must not be negative".to_string()));
+        }
+        // Unit vector along the line and its normal.
+        let (ux, uy) = ((x2 - x1) / len, (y2 - y1) / len);
+        let (nx, ny) = (-uy, ux);
+        // The shaft stops at the arrowhead's base so wide strokes don't
+        // poke out past the tip.
+        let (bx, by) = (x2 - ux * head_size.min(len), y2 - uy * head_size.min(len));
+        let mut pb = PathBuilder::new();
+        pb.move_to(x1, y1);
+        pb.line_to(bx, by);
+        let shaft = pb.finish();
+        let half = head_size / 2.;
+        let mut pb = PathBuilder::new();
+        pb.move_to(x2, y2);
+        pb.line_to(x2 - ux * head_size + nx * half, y2 - uy * head_size + ny * half);
+        pb.line_to(x2 - ux * head_size - nx * half, y2 - uy * head_size - ny * half);
+        pb.close();
+        let head = pb.finish();
+        with_target(id, |target| {
+            src.draw(target, |target, src| {
+                target.stroke(&shaft, src, &stroke, &DrawOptions::new());
+                target.fill(&head, src, &DrawOptions::new());
+            });
+            Ok(ok_response())
+        })
+    })
+}
+
+struct LoadedFont {
+    font: fontdue::Font,
+    // fontdue only rasterizes, so glyph outlines are read with ttf-parser.
+    data: Vec<u8>
+}
+
+// Maps font units (y up) into user space at the given origin and scale.
+struct GlyphPathBuilder<'a> {
+    pb: &'a mut PathBuilder,
+    x: f32,
+    y: f32,
+    scale: f32
+}
+
+impl<'a> GlyphPathBuilder<'a> {
+    fn map(&self, x: f32, y: f32) -> (f32, f32) {
+        (self.x + x * self.scale, self.y - y * self.scale)
+    }
+}
+
+impl<'a> ttf_parser::OutlineBuilder for GlyphPathBuilder<'a> {
+    fn move_to(&mut self, x: f32, y: f32) {
+        let (x, y) = self.map(x, y);
+        self.pb.move_to(x, y);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let (x, y) = self.map(x, y);
+        self.pb.line_to(x, y);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (x1, y1) = self.map(x1, y1);
+        let (x, y) = self.map(x, y);
+        self.pb.quad_to(x1, y1, x, y);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let (x1, y1) = self.map(x1, y1);
+        let (x2, y2) = self.map(x2, y2);
+        let (x, y) = self.map(x, y);
+        self.pb.cubic_to(x1, y1, x2, y2, x, y);
+    }
+
+    fn close(&mut self) {
+        self.pb.close();
+    }
+}
+
+fn parse_face(font: &LoadedFont) -> PluginResult<ttf_parser::Face> {
+    ttf_parser::Face::from_slice(&font.data, 0)
+        .map_err(|e| PluginError::Invalid(format!("failed to parse font: {}", e)))
+}
+
+// Outlines a single line of text with its baseline starting at (x, y),
+// advancing by the same glyph metrics that fill_text uses.
+fn text_path(font: &LoadedFont, text: &str, x: f32, y: f32, size: f32) -> PluginResult<Path> {
+    let face = parse_face(font)?;
+    let scale = size / face.units_per_em().unwrap_or(1000) as f32;
+    let mut pb = PathBuilder::new();
+    let mut pen_x = x;
+    for c in text.chars() {
+        if let Some(glyph) = face.glyph_index(c) {
+            face.outline_glyph(glyph, &mut GlyphPathBuilder { pb: &mut pb, x: pen_x, y, scale });
+        }
+        pen_x += font.font.metrics(c, size).advance_width;
+    }
+    Ok(pb.finish())
+}
+
+#[derive(Serialize)]
+struct TextMetrics {
+    width: f32,
+    height: f32,
+    ascent: f32,
+    descent: f32,
+    line_height: f32
+}
+
+fn measure_text(font: &fontdue::Font, text: &str, size: f32) -> TextMetrics {
+    let width = text.chars().map(|c| font.metrics(c, size).advance_width).sum();
+    // fontdue reports descent as a negative offset below the baseline.
+    let (ascent, descent, line_height) = match font.horizontal_line_metrics(size) {
+        Some(m) => (m.ascent, m.descent, m.new_line_size),
+        None => (size, 0., size),
+    };
+    TextMetrics { width, height: ascent - descent, ascent, descent, line_height }
+}
+
+enum TextAlign {
+    Left,
+    Center,
+    Right,
+}
+
+impl TextAlign {
+    // Offset to add to x so the text's anchor lands on x.
+    fn offset(&self, width: f32) -> f32 {
+        match self {
+            TextAlign::Left => 0.,
+            TextAlign::Center => -width / 2.,
+            TextAlign::Right => -width,
+        }
+    }
+}
+
+// Alignment is optional and defaults to left when the argument is absent.
+fn get_arg_align(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<TextAlign> {
+    if args.len() <= idx {
+        return Ok(TextAlign::Left);
+    }
+    match get_arg_str(args, idx)?.as_str() {
+        "left" => Ok(TextAlign::Left),
+        "center" => Ok(TextAlign::Center),
+        "right" => Ok(TextAlign::Right),
+        other => Err(PluginError::InvalidArg(idx, format!("unknown alignment `{}`", other))),
+    }
+}
+
+// Greedy word wrap. Words are split on spaces and never broken, so a word
+// wider than max_width gets a line of its own. Newlines force a break.
+fn wrap_text(font: &fontdue::Font, text: &str, size: f32, max_width: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        for word in paragraph.split(' ').filter(|word| !word.is_empty()) {
+            if line.is_empty() {
+                line.push_str(word);
+                continue;
+            }
+            let candidate = format!("{} {}", line, word);
+            if measure_text(font, &candidate, size).width > max_width {
+                lines.push(std::mem::replace(&mut line, word.to_string()));
+            } else {
+                line = candidate;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// Rasterizes a single line of text with its baseline starting at (x, y) and
+// masks the source through each glyph's coverage. Glyph positions follow the
+// target's transform but glyphs are rasterized unscaled in device space.
+fn fill_text(target: &mut DrawTarget, font: &fontdue::Font, text: &str, x: f32, y: f32, size: f32, src: &Source) {
+    let origin = target.get_transform().transform_point(Point::new(x, y));
+    let mut pen_x = origin.x;
+    for c in text.chars() {
+        let (metrics, coverage) = font.rasterize(c, size);
+        if metrics.width > 0 && metrics.height > 0 {
+            // fontdue measures ymin upwards from the baseline.
+            let gx = (pen_x + metrics.xmin as f32).round() as i32;
+            let gy = (origin.y - (metrics.ymin + metrics.height as i32) as f32).round() as i32;
+            target.mask(src, gx, gy, &Mask {
+                width: metrics.width as i32,
+                height: metrics.height as i32,
+                data: coverage
+            });
+        }
+        pen_x += metrics.advance_width;
+    }
+}
+
+fn op_dt_fill_text(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let text = get_arg_str(_args, 1)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let font_id = get_arg_u32(_args, 4)?;
+        let size = get_arg_f32(_args, 5)?;
+        if size <= 0. {
+            return Err(PluginError::Invalid("font size must be positive".to_string()));
+        }
+        let src = get_arg_src(_args, 6, 8)?;
+        let align = get_arg_align(_args, 7)?;
+        with_font(font_id, |font| {
+            let x = x + align.offset(measure_text(&font.font, &text, size).width);
+            with_target(id, |target| {
+                src.draw(target, |target, src| fill_text(target, &font.font, &text, x, y, size, src));
+                Ok(ok_response())
+            })
+        })
+    })
+}
+
+fn op_font_load(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let bytes = get_arg_buf(_args, 1)?;
+        let font = fontdue::Font::from_bytes(bytes, fontdue::FontSettings::default())
+            .map_err(|e| PluginError::Invalid(format!("failed to parse font: {}", e)))?;
+        FONTS.with(|map| map.borrow_mut().insert(id, LoadedFont { font, data: bytes.to_vec() }));
+        Ok(ok_response())
+    })
+}
+
+fn op_font_destroy(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        FONTS.with(|map| {
+            map.borrow_mut().remove(&id).ok_or(PluginError::FontNotFound(id))?;
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_measure_text(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let font_id = get_arg_u32(_args, 0)?;
+        let text = get_arg_str(_args, 1)?;
+        let size = get_arg_f32(_args, 2)?;
+        if size <= 0. {
+            return Err(PluginError::Invalid("font size must be positive".to_string()));
+        }
+        with_font(font_id, |font| Ok(value_response(measure_text(&font.font, &text, size))))
+    })
+}
+
+fn op_dt_stroke_text(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let text = get_arg_str(_args, 1)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let font_id = get_arg_u32(_args, 4)?;
+        let size = get_arg_f32(_args, 5)?;
+        if size <= 0. {
+            return Err(PluginError::Invalid("font size must be positive".to_string()));
+        }
+        let src = get_arg_src(_args, 6, 9)?;
+        let stroke = stroke_style_from_json(get_arg_json(_args, 7)?);
+        let align = get_arg_align(_args, 8)?;
+        with_font(font_id, |font| {
+            let x = x + align.offset(measure_text(&font.font, &text, size).width);
+            let path = text_path(font, &text, x, y, size)?;
+            with_target(id, |target| {
+                src.draw(target, |target, src| target.stroke(&path, src, &stroke, &DrawOptions::new()));
+                Ok(ok_response())
+            })
+        })
+    })
+}
+
+fn op_dt_fill_text_wrapped(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let text = get_arg_str(_args, 1)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let max_width = get_arg_f32(_args, 4)?;
+        let line_height = get_arg_f32(_args, 5)?;
+        let font_id = get_arg_u32(_args, 6)?;
+        let size = get_arg_f32(_args, 7)?;
+        if size <= 0. {
+            return Err(PluginError::Invalid("font size must be positive".to_string()));
+        }
+        let src = get_arg_src(_args, 8, 10)?;
+        let align = get_arg_align(_args, 9)?;
+        with_font(font_id, |font| {
+            let lines = wrap_text(&font.font, &text, size, max_width);
+            with_target(id, |target| {
+                src.draw(target, |target, src| {
+                    for (i, line) in lines.iter().enumerate() {
+                        let x = x + align.offset(measure_text(&font.font, line, size).width);
+                        fill_text(target, &font.font, line, x, y + line_height * i as f32, size, src);
+                    }
+                });
+                Ok(value_response(lines.len()))
+            })
+        })
+    })
+}
+
+fn op_dt_get_text_path(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let text = get_arg_str(_args, 0)?;
+        let font_id = get_arg_u32(_args, 1)?;
+        let size = get_arg_f32(_args, 2)?;
+        let x = get_arg_f32(_args, 3)?;
+        let y = get_arg_f32(_args, 4)?;
+        if size <= 0. {
+            return Err(PluginError::Invalid("font size must be positive".to_string()));
+        }
+        with_font(font_id, |font| {
+            let path = text_path(font, &text, x, y, size)?;
+            Ok(value_response(path_to_json(&path)))
+        })
+    })
+}
+
+fn op_dt_draw_text_on_path(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let text = get_arg_str(_args, 1)?;
+        let path = get_arg_path(_args, 2)?;
+        let font_id = get_arg_u32(_args, 3)?;
+        let size = get_arg_f32(_args, 4)?;
+        if size <= 0. {
+            return Err(PluginError::Invalid("font size must be positive".to_string()));
+        }
+        let src = get_arg_src(_args, 5, 6)?;
+        let measure = PathMeasure::new(&path);
+        with_font(font_id, |font| {
+            // Each glyph is outlined around its horizontal centre on the
+            // baseline, then rotated to the tangent at its centre's distance
+            // along the path. Glyphs past the end of the path are dropped.
+            let face = parse_face(font)?;
+            let scale = size / face.units_per_em().unwrap_or(1000) as f32;
+            let mut glyphs = PathBuilder::new();
+            let mut pen = 0.;
+            for c in text.chars() {
+                let advance = font.font.metrics(c, size).advance_width;
+                let placement = measure.pos_tan(pen + advance / 2.);
+                pen += advance;
+                let ((p, angle), glyph_id) = match (placement, face.glyph_index(c)) {
+                    (Some(placement), Some(glyph_id)) => (placement, glyph_id),
+                    _ => continue,
+                };
+                let transform = Transform::create_rotation(euclid::Angle::radians(angle))
+                    .post_transform(&Transform::create_translation(p.x, p.y));
+                let mut pb = PathBuilder::new();
+                face.outline_glyph(glyph_id, &mut GlyphPathBuilder { pb: &mut pb, x: -advance / 2., y: 0., scale });
+                for op in pb.finish().transform(&transform).ops {
+                    match op {
+                        PathOp::MoveTo(p) => glyphs.move_to(p.x, p.y),
+                        PathOp::LineTo(p) => glyphs.line_to(p.x, p.y),
+                        PathOp::QuadTo(c, p) => glyphs.quad_to(c.x, c.y, p.x, p.y),
+                        PathOp::CubicTo(c1, c2, p) => glyphs.cubic_to(c1.x, c1.y, c2.x, c2.y, p.x, p.y),
+                        PathOp::Close => glyphs.close(),
+                    }
+                }
+            }
+            let glyphs = glyphs.finish();
+            with_target(id, |target| {
+                src.draw(target, |target, src| target.fill(&glyphs, src, &DrawOptions::new()));
+                Ok(ok_response())
+            })
+        })
+    })
+}
+
+fn op_dt_draw_image_fit(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let w = get_arg_f32(_args, 4)?;
+        let h = get_arg_f32(_args, 5)?;
+        let mode = get_arg_str(_args, 6)?;
+        let anchor_x = get_arg_f32_or(_args, 7, 0.5)?;
+        let anchor_y = get_arg_f32_or(_args, 8, 0.5)?;
+        if !(0.0..=1.0).contains(&anchor_x) || !(0.0..=1.0).contains(&anchor_y) {
+            return Err(PluginError::Invalid("anchor must be within [0, 1]".to_string()));
+        }
+        let img = get_arg_img(_args, 1)?;
+        let (iw, ih) = (img.width as f32, img.height as f32);
+        let scale = match mode.as_str() {
+            "contain" => (w / iw).min(h / ih),
+            "cover" => (w / iw).max(h / ih),
+            "fill" => 1.,
+            other => return Err(PluginError::InvalidArg(6, format!("unknown fit mode `{}`", other))),
+        };
+        // Like CSS object-position, the anchor places the scaled image within
+        // the leftover (contain) or overflowing (cover) space.
+        let (dw, dh) = if mode == "fill" { (w, h) } else { (iw * scale, ih * scale) };
+        let (dx, dy) = (x + (w - dw) * anchor_x, y + (h - dh) * anchor_y);
+        let image = Image { width: img.width as i32, height: img.height as i32, data: &*img.data };
+        with_target(id, |target| {
+            if mode == "cover" {
+                let mut pb = PathBuilder::new();
+                pb.rect(x, y, w, h);
+                target.push_clip(&pb.finish());
+                target.draw_image_with_size_at(dx, dy, dw, dh, &image, &DrawOptions::new());
+                target.pop_clip();
+            } else {
+                target.draw_image_with_size_at(dx, dy, dw, dh, &image, &DrawOptions::new());
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+// Rasterizes an SVG at exactly w x h pixels into premultiplied ARGB, which is
+// what both tiny-skia (as RGBA) and raqote use.
+fn render_svg(tree: &usvg::Tree, w: u32, h: u32) -> PluginResult<Vec<u32>> {
+    let mut pixmap = tiny_skia::Pixmap::new(w, h)
+        .ok_or_else(|| PluginError::Invalid(format!("invalid SVG size {}x{}", w, h)))?;
+    resvg::render(tree, usvg::FitTo::Size(w, h), pixmap.as_mut())
+        .ok_or_else(|| PluginError::Image("failed to render SVG".to_string()))?;
+    Ok(pixmap.data().chunks(4).map(|c| {
+        ((c[3] as u32) << 24) | ((c[0] as u32) << 16) | ((c[1] as u32) << 8) | (c[2] as u32)
+    }).collect())
+}
+
+fn op_dt_draw_svg_at(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let w = get_arg_u32(_args, 4)?;
+        let h = get_arg_u32(_args, 5)?;
+        let options = get_arg_draw_options(_args, 6)?;
+        let tree = usvg::Tree::from_data(get_arg_buf(_args, 1)?, &usvg::Options::default())
+            .map_err(|e| PluginError::Image(format!("failed to parse SVG: {}", e)))?;
+        let data = render_svg(&tree, w, h)?;
+        with_target(id, |target| {
+            target.draw_image_at(x, y, &Image { width: w as i32, height: h as i32, data: &data }, &options);
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_svg_string(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let svg = get_arg_str(_args, 1)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let w = get_arg_u32(_args, 4)?;
+        let h = get_arg_u32(_args, 5)?;
+        let options = get_arg_draw_options(_args, 6)?;
+        let tree = usvg::Tree::from_str(&svg, &usvg::Options::default())
+            .map_err(|e| PluginError::Image(format!("failed to parse SVG: {}", e)))?;
+        let data = render_svg(&tree, w, h)?;
+        with_target(id, |target| {
+            target.draw_image_at(x, y, &Image { width: w as i32, height: h as i32, data: &data }, &options);
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_encode_qoi(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            let buf = qoi::encode_to_vec(target_rgba(target), target.width() as u32, target.height() as u32)
+                .map_err(|e| PluginError::Image(e.to_string()))?;
+            Ok(Op::Sync(buf.into_boxed_slice()))
+        })
+    })
+}
+
+// png only offers presets, so the IDAT stream is deflated here at the exact
+// zlib level and written as a raw chunk.
+fn encode_png_with_level(width: u32, height: u32, rgba: &[u8], level: u8) -> PluginResult<Vec<u8>> {
+    let filtered = png_filter_rows(rgba, width as usize, 4);
+    let idat = zlib_compress(&filtered, level);
+    let to_err = |e: png::EncodingError| PluginError::Image(e.to_string());
+    let mut buf = Vec::new();
+    {
+        // IEND is written when the writer is dropped.
+        let mut encoder = png::Encoder::new(&mut buf, width, height);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(to_err)?;
+        writer.write_chunk(*b"IDAT", &idat).map_err(to_err)?;
+    }
+    Ok(buf)
+}
+
+fn op_dt_encode_png_with_level(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let level = get_arg_u8(_args, 1)?;
+        if level > 9 {
+            return Err(PluginError::Invalid("compression level must be within [0, 9]".to_string()));
+        }
+        with_target(id, |target| {
+            let buf = encode_png_with_level(target.width() as u32, target.height() as u32, &target_rgba(target), level)?;
+            Ok(Op::Sync(buf.into_boxed_slice()))
+        })
+    })
+}
+
+fn op_dt_encode_png_grayscale(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            // Rec. 709 luma of the unpremultiplied colour; alpha is dropped.
+            let luma: Vec<u8> = target.get_data().iter().map(|&pixel| {
+                let [_, r, g, b] = unpremultiply(pixel);
+                (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32).round() as u8
+            }).collect();
+            let buf = encode_png(target.width() as u32, target.height() as u32, png::ColorType::Grayscale, &luma, png::Compression::Default)?;
+            Ok(Op::Sync(buf.into_boxed_slice()))
+        })
+    })
+}
+
+fn op_dt_encode_png_alpha_only(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            // Premultiplication only scales colour, so alpha is already straight.
+            let alpha: Vec<u8> = target.get_data().iter().map(|&pixel| (pixel >> 24) as u8).collect();
+            let buf = encode_png(target.width() as u32, target.height() as u32, png::ColorType::Grayscale, &alpha, png::Compression::Default)?;
+            Ok(Op::Sync(buf.into_boxed_slice()))
+        })
+    })
+}
+
+fn op_dt_encode_with_icc_profile(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let profile = get_arg_buf(_args, 1)?;
+        if profile.is_empty() {
+            return Err(PluginError::Invalid("ICC profile is empty".to_string()));
+        }
+        // iCCP: profile name, NUL, compression method 0 (zlib), compressed profile.
+        let mut iccp = b"ICC profile\0\0".to_vec();
+        iccp.extend(deflate::deflate_bytes_zlib(profile));
+        with_target(id, |target| {
+            let buf = encode_png_with_chunks(
+                target.width() as u32,
+                target.height() as u32,
+                png::ColorType::RGBA,
+                &target_rgba(target),
+                png::Compression::Default,
+                &[(*b"iCCP", iccp)],
+            )?;
+            Ok(Op::Sync(buf.into_boxed_slice()))
+        })
+    })
+}
+
+fn op_dt_encode_tiff(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    use tiff::encoder::{colortype::RGBA8, compression::{Deflate, DeflateLevel, Lzw, Uncompressed}, TiffEncoder};
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let compression = get_arg_str(_args, 1)?;
+        with_target(id, |target| {
+            let (width, height) = (target.width() as u32, target.height() as u32);
+            let data = target_rgba(target);
+            let mut buf = std::io::Cursor::new(Vec::new());
+            {
+                let mut encoder = TiffEncoder::new(&mut buf).map_err(|e| PluginError::Image(e.to_string()))?;
+                match compression.as_str() {
+                    "none" => encoder.write_image_with_compression::<RGBA8, _>(width, height, Uncompressed, &data),
+                    "lzw" => encoder.write_image_with_compression::<RGBA8, _>(width, height, Lzw, &data),
+                    "deflate" => encoder.write_image_with_compression::<RGBA8, _>(width, height, Deflate::with_level(DeflateLevel::Balanced), &data),
+                    other => return Err(PluginError::InvalidArg(1, format!("unknown TIFF compression `{}`", other))),
+                }.map_err(|e| PluginError::Image(e.to_string()))?;
+            }
+            Ok(Op::Sync(buf.into_inner().into_boxed_slice()))
+        })
+    })
+}
+
+fn op_dt_encode_ico(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    const SIZES: [u32; 4] = [16, 32, 48, 256];
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            let (width, height) = (target.width() as u32, target.height() as u32);
+            let source = image::RgbaImage::from_raw(width, height, target_rgba(target))
+                .ok_or_else(|| PluginError::Image("invalid target size".to_string()))?;
+            let mut images = Vec::with_capacity(SIZES.len());
+            for &size in SIZES.iter() {
+                // The longer side fills the icon and the image is centred on
+                // transparency, so non-square targets keep their aspect ratio.
+                let scale = size as f32 / width.max(height) as f32;
+                let fit = |v: u32| ((v as f32 * scale).round() as u32).max(1).min(size);
+                let (fit_w, fit_h) = (fit(width), fit(height));
+                let resized = image::imageops::resize(&source, fit_w, fit_h, image::imageops::FilterType::Triangle);
+                let (left, top) = ((size - fit_w) as usize / 2, (size - fit_h) as usize / 2);
+                let mut icon = vec![0u8; (size * size * 4) as usize];
+                for (y, row) in resized.as_raw().chunks_exact(fit_w as usize * 4).enumerate() {
+                    let start = ((top + y) * size as usize + left) * 4;
+                    icon[start..start + row.len()].copy_from_slice(row);
+                }
+                images.push(encode_png(size, size, png::ColorType::RGBA, &icon, png::Compression::Default)?);
+            }
+            // ICONDIR, one ICONDIRENTRY per size, then the PNG-compressed images.
+            let mut buf = Vec::new();
+            buf.extend_from_slice(&0u16.to_le_bytes());
+            buf.extend_from_slice(&1u16.to_le_bytes());
+            buf.extend_from_slice(&(SIZES.len() as u16).to_le_bytes());
+            let mut offset = 6 + 16 * SIZES.len() as u32;
+            for (&size, png) in SIZES.iter().zip(&images) {
+                // A dimension of 0 means 256.
+                buf.push(size as u8);
+                buf.push(size as u8);
+                buf.push(0);
+                buf.push(0);
+                buf.extend_from_slice(&1u16.to_le_bytes());
+                buf.extend_from_slice(&32u16.to_le_bytes());
+                buf.extend_from_slice(&(png.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&offset.to_le_bytes());
+                offset += png.len() as u32;
+            }
+            for png in images {
+                buf.extend(png);
+            }
+            Ok(Op::Sync(buf.into_boxed_slice()))
+        })
+    })
+}
+
+struct GifState {
+    repeat: gif::Repeat,
+    frames: Vec<gif::Frame<'static>>,
+}
+
+// A median-cut box along with its widest channel, measured once when the box
+// is created rather than on every split.
+struct ColorBox {
+    colors: Vec<([u8; 3], u32)>,
+    channel: usize,
+    range: u8,
+}
+
+impl ColorBox {
+    fn new(colors: Vec<([u8; 3], u32)>) -> ColorBox {
+        let mut min = [255u8; 3];
+        let mut max = [0u8; 3];
+        for (c, _) in &colors {
+            for ((lo, hi), &v) in min.iter_mut().zip(max.iter_mut()).zip(c.iter()) {
+                *lo = (*lo).min(v);
+                *hi = (*hi).max(v);
+            }
+        }
+        let mut channel = 0;
+        let mut range = 0;
+        for (i, (lo, hi)) in min.iter().zip(max.iter()).enumerate() {
+            if hi.saturating_sub(*lo) > range {
+                channel = i;
+                range = hi - lo;
+            }
+        }
+        ColorBox { colors, channel, range }
+    }
+}
+
+// Median-cut quantization of the opaque colors to at most 255 palette
+// entries; the last palette index is reserved for transparent pixels.
+fn quantize_median_cut(rgba: &[u8]) -> (Vec<u8>, Vec<u8>, u8) {
+    const MAX_COLORS: usize = 255;
+    let mut histogram: HashMap<[u8; 3], u32> = HashMap::new();
+    for px in rgba.chunks_exact(4) {
+        if px[3] >= 128 {
+            *histogram.entry([px[0], px[1], px[2]]).or_insert(0) += 1;
+        }
+    }
+    let mut boxes = vec![ColorBox::new(histogram.into_iter().collect())];
+    while boxes.len() < MAX_COLORS {
+        // Split the box with the widest channel range at its weighted median.
+        let mut best: Option<(usize, u8)> = None;
+        for (i, b) in boxes.iter().enumerate() {
+            if b.colors.len() >= 2 && best.map_or(true, |(_, r)| b.range > r) {
+                best = Some((i, b.range));
+            }
+        }
+        let index = match best {
+            Some((i, _)) => i,
+            None => break,
+        };
+        let ColorBox { mut colors, channel, .. } = boxes.swap_remove(index);
+        colors.sort_unstable_by_key(|(c, _)| c[channel]);
+        let total: u64 = colors.iter().map(|&(_, n)| n as u64).sum();
+        let mut acc = 0u64;
+        let mut split = colors.len() - 1;
+        for (i, &(_, n)) in colors.iter().enumerate() {
+            acc += n as u64;
+            if acc * 2 >= total {
+                split = i + 1;
+                break;
+            }
+        }
+        let split = split.max(1).min(colors.len() - 1);
+        let upper = colors.split_off(split);
+        boxes.push(ColorBox::new(colors));
+        boxes.push(ColorBox::new(upper));
+    }
+
+    let mut palette = Vec::with_capacity(256 * 3);
+    let mut lookup: HashMap<[u8; 3], u8> = HashMap::new();
+    for (i, colors) in boxes.iter().map(|b| &b.colors).filter(|colors| !colors.is_empty()).enumerate() {
+        let mut sum = [0u64; 3];
+        let mut count = 0u64;
+        for &(c, n) in colors {
+            for (s, &v) in sum.iter_mut().zip(c.iter()) {
+                *s += v as u64 * n as u64;
+            }
+            count += n as u64;
+            lookup.insert(c, i as u8);
+        }
+        for s in sum.iter() {
+            palette.push((s / count) as u8);
+        }
+    }
+    let transparent = (palette.len() / 3) as u8;
+    palette.extend_from_slice(&[0, 0, 0]);
+
+    let indices = rgba
+        .chunks_exact(4)
+        .map(|px| {
+            if px[3] >= 128 {
+                lookup[&[px[0], px[1], px[2]]]
+            } else {
+                transparent
+            }
+        })
+        .collect();
+    (palette, indices, transparent)
+}
+
+fn op_gif_create(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let loop_count = get_arg_u32(_args, 1)?;
+        let repeat = match loop_count {
+            0 => gif::Repeat::Infinite,
+            n if n <= u16::MAX as u32 => gif::Repeat::Finite(n as u16),
+            _ => return Err(PluginError::InvalidArg(1, "loop count must fit in 16 bits".to_string())),
+        };
+        GIFS.with(|map| map.borrow_mut().insert(id, GifState { repeat, frames: Vec::new() }));
+        Ok(ok_response())
+    })
+}
+
+fn op_gif_add_frame(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let gif_id = get_arg_u32(_args, 0)?;
+        let target_id = get_arg_u32(_args, 1)?;
+        let delay = get_arg_u32(_args, 2)?;
+        if delay > u16::MAX as u32 {
+            return Err(PluginError::InvalidArg(2, "delay must fit in 16 bits".to_string()));
+        }
+        let (width, height, rgba) = with_target(target_id, |target| {
+            Ok((target.width() as u16, target.height() as u16, target_rgba(target)))
+        })?;
+        GIFS.with(|map| {
+            let mut gifs = map.borrow_mut();
+            let state = gifs.get_mut(&gif_id).ok_or(PluginError::GifNotFound(gif_id))?;
+            if let Some(first) = state.frames.first() {
+                if (first.width, first.height) != (width, height) {
+                    return Err(PluginError::Invalid(format!(
+                        "frame size {}x{} does not match {}x{}",
+                        width, height, first.width, first.height
+                    )));
+                }
+            }
+            let (palette, indices, transparent) = quantize_median_cut(&rgba);
+            state.frames.push(gif::Frame {
+                width,
+                height,
+                delay: delay as u16,
+                dispose: gif::DisposalMethod::Background,
+                transparent: Some(transparent),
+                palette: Some(palette),
+                buffer: indices.into(),
+                ..gif::Frame::default()
+            });
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_gif_destroy(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        GIFS.with(|map| {
+            map.borrow_mut().remove(&id).ok_or(PluginError::GifNotFound(id))?;
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_gif_finish(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let state = GIFS
+            .with(|map| map.borrow_mut().remove(&id))
+            .ok_or(PluginError::GifNotFound(id))?;
+        let (width, height) = match state.frames.first() {
+            Some(frame) => (frame.width, frame.height),
+            None => return Err(PluginError::Invalid(format!("gif {} has no frames", id))),
+        };
+        let mut buf = Vec::new();
+        {
+            // The trailer is written when the encoder is dropped.
+            let to_err = |e: gif::EncodingError| PluginError::Image(e.to_string());
+            let mut encoder = gif::Encoder::new(&mut buf, width, height, &[]).map_err(to_err)?;
+            encoder.set_repeat(state.repeat).map_err(to_err)?;
+            for frame in state.frames.iter() {
+                encoder.write_frame(frame).map_err(to_err)?;
+            }
+        }
+        Ok(Op::Sync(buf.into_boxed_slice()))
+    })
+}
+
+struct ApngFrame {
+    data: Vec<u8>,
+    delay_num: u16,
+    delay_den: u16,
+}
+
+#[derive(Default)]
+struct ApngState {
+    size: Option<(u32, u32)>,
+    frames: Vec<ApngFrame>,
+}
+
+// Concatenated payload of every IDAT chunk in an encoded PNG.
+fn png_idat(png: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut pos = 8;
+    while pos + 8 <= png.len() {
+        let len = u32::from_be_bytes([png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]) as usize;
+        let end = (pos + 8 + len).min(png.len());
+        if &png[pos + 4..pos + 8] == b"IDAT" {
+            data.extend_from_slice(&png[pos + 8..end]);
+        }
+        pos = end + 4;
+    }
+    data
+}
+
+fn op_apng_create(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        APNGS.with(|map| map.borrow_mut().insert(id, ApngState::default()));
+        Ok(ok_response())
+    })
+}
+
+fn op_apng_add_frame(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let apng_id = get_arg_u32(_args, 0)?;
+        let target_id = get_arg_u32(_args, 1)?;
+        let mut delay = [0u16; 2];
+        for (i, d) in delay.iter_mut().enumerate() {
+            let value = get_arg_u32(_args, 2 + i)?;
+            if value > u16::MAX as u32 {
+                return Err(PluginError::InvalidArg(2 + i, "delay must fit in 16 bits".to_string()));
+            }
+            *d = value as u16;
+        }
+        let (size, png) = with_target(target_id, |target| {
+            let (width, height) = (target.width() as u32, target.height() as u32);
+            let png = encode_png(width, height, png::ColorType::RGBA, &target_rgba(target), png::Compression::Default)?;
+            Ok(((width, height), png))
+        })?;
+        APNGS.with(|map| {
+            let mut apngs = map.borrow_mut();
+            let state = apngs.get_mut(&apng_id).ok_or(PluginError::ApngNotFound(apng_id))?;
+            match state.size {
+                Some(first) if first != size => {
+                    return Err(PluginError::Invalid(format!(
+                        "frame size {}x{} does not match {}x{}",
+                        size.0, size.1, first.0, first.1
+                    )))
+                }
+                _ => state.size = Some(size),
+            }
+            state.frames.push(ApngFrame {
+                data: png_idat(&png),
+                delay_num: delay[0],
+                delay_den: delay[1],
+            });
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_apng_destroy(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        APNGS.with(|map| {
+            map.borrow_mut().remove(&id).ok_or(PluginError::ApngNotFound(id))?;
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_apng_finish(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let state = APNGS
+            .with(|map| map.borrow_mut().remove(&id))
+            .ok_or(PluginError::ApngNotFound(id))?;
+        let (width, height) = state
+            .size
+            .ok_or_else(|| PluginError::Invalid(format!("apng {} has no frames", id)))?;
+        let to_err = |e: png::EncodingError| PluginError::Image(e.to_string());
+        let mut buf = Vec::new();
+        {
+            // IEND is written when the writer is dropped.
+            let mut encoder = png::Encoder::new(&mut buf, width, height);
+            encoder.set_color(png::ColorType::RGBA);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().map_err(to_err)?;
+            let mut actl = Vec::with_capacity(8);
+            actl.extend_from_slice(&(state.frames.len() as u32).to_be_bytes());
+            actl.extend_from_slice(&0u32.to_be_bytes());
+            writer.write_chunk(*b"acTL", &actl).map_err(to_err)?;
+            // fcTL and fdAT chunks share one sequence counter.
+            let mut sequence = 0u32;
+            for (i, frame) in state.frames.iter().enumerate() {
+                let mut fctl = Vec::with_capacity(26);
+                fctl.extend_from_slice(&sequence.to_be_bytes());
+                fctl.extend_from_slice(&width.to_be_bytes());
+                fctl.extend_from_slice(&height.to_be_bytes());
+                fctl.extend_from_slice(&0u32.to_be_bytes());
+                fctl.extend_from_slice(&0u32.to_be_bytes());
+                fctl.extend_from_slice(&frame.delay_num.to_be_bytes());
+                fctl.extend_from_slice(&frame.delay_den.to_be_bytes());
+                // APNG_DISPOSE_OP_NONE, APNG_BLEND_OP_SOURCE
+                fctl.extend_from_slice(&[0, 0]);
+                writer.write_chunk(*b"fcTL", &fctl).map_err(to_err)?;
+                sequence += 1;
+                if i == 0 {
+                    writer.write_chunk(*b"IDAT", &frame.data).map_err(to_err)?;
+                } else {
+                    let mut fdat = Vec::with_capacity(4 + frame.data.len());
+                    fdat.extend_from_slice(&sequence.to_be_bytes());
+                    fdat.extend_from_slice(&frame.data);
+                    writer.write_chunk(*b"fdAT", &fdat).map_err(to_err)?;
+                    sequence += 1;
+                }
+            }
+        }
+        Ok(Op::Sync(buf.into_boxed_slice()))
+    })
+}
+
+fn op_dt_render_to_pdf_page(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let dpi = get_arg_f32(_args, 1)?;
+        if dpi <= 0.0 {
+            return Err(PluginError::InvalidArg(1, "dpi must be positive".to_string()));
+        }
+        with_target(id, |target| {
+            let (width, height) = (target.width() as u32, target.height() as u32);
+            let mut rgb = Vec::with_capacity(target.get_data().len() * 3);
+            let mut alpha = Vec::with_capacity(target.get_data().len());
+            for &pixel in target.get_data() {
+                let [a, r, g, b] = unpremultiply(pixel);
+                rgb.extend_from_slice(&[r, g, b]);
+                alpha.push(a);
+            }
+            // PNG IDAT data is a zlib stream that PDF decodes directly with
+            // FlateDecode and the PNG predictors.
+            let color = png_idat(&encode_png(width, height, png::ColorType::RGB, &rgb, png::Compression::Default)?);
+            let mask = png_idat(&encode_png(width, height, png::ColorType::Grayscale, &alpha, png::Compression::Default)?);
+            let page_width = width as f32 * 72.0 / dpi;
+            let page_height = height as f32 * 72.0 / dpi;
+            let content = format!("q {} 0 0 {} 0 0 cm /Im0 Do Q", page_width, page_height);
+
+            let image_dict = |colors: u32, extra: &str, len: usize| {
+                format!(
+                    "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /{} /BitsPerComponent 8 \
+                     /Filter /FlateDecode /DecodeParms << /Predictor 15 /Colors {} /BitsPerComponent 8 /Columns {} >>{} /Length {} >>",
+                    width,
+                    height,
+                    if colors == 3 { "DeviceRGB" } else { "DeviceGray" },
+                    colors,
+                    width,
+                    extra,
+                    len
+                )
+            };
+            let objects: Vec<(String, &[u8])> = vec![
+                ("<< /Type /Catalog /Pages 2 0 R >>".to_string(), &[]),
+                ("<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(), &[]),
+                (
+                    format!(
+                        "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /XObject << /Im0 4 0 R >> >> /Contents 5 0 R >>",
+                        page_width, page_height
+                    ),
+                    &[],
+                ),
+                (image_dict(3, " /SMask 6 0 R", color.len()), &color),
+                (format!("<< /Length {} >>", content.len()), content.as_bytes()),
+                (image_dict(1, "", mask.len()), &mask),
+            ];
+
+            let mut buf = b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n".to_vec();
+            let mut offsets = Vec::with_capacity(objects.len());
+            for (i, (dict, stream)) in objects.iter().enumerate() {
+                offsets.push(buf.len());
+                buf.extend_from_slice(format!("{} 0 obj\n{}\n", i + 1, dict).as_bytes());
+                if dict.contains("/Length") {
+                    buf.extend_from_slice(b"stream\n");
+                    buf.extend_from_slice(stream);
+                    buf.extend_from_slice(b"\nendstream\n");
+                }
+                buf.extend_from_slice(b"endobj\n");
+            }
+            let xref = buf.len();
+            buf.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+            for offset in offsets {
+                buf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+            }
+            buf.extend_from_slice(
+                format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).as_bytes(),
+            );
+            Ok(Op::Sync(buf.into_boxed_slice()))
+        })
+    })
+}
+
+fn op_dt_generate_qr_code(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 1)?;
+        let y = get_arg_f32(_args, 2)?;
+        let cell_size = get_arg_u32(_args, 3)? as f32;
+        let data = get_arg_str(_args, 4)?;
+        // Pattern images follow both sources: the foreground's at 7 and the
+        // background's at 8.
+        let fg = get_arg_src(_args, 5, 7)?;
+        let bg = get_arg_src(_args, 6, 8)?;
+        let code = qrcode::QrCode::new(data.as_bytes())
+            .map_err(|e| PluginError::InvalidArg(4, e.to_string()))?;
+        let width = code.width();
+        // Cells of each colour are merged into one path so every source is
+        // only drawn once.
+        let mut dark = PathBuilder::new();
+        let mut light = PathBuilder::new();
+        for (i, color) in code.to_colors().into_iter().enumerate() {
+            let pb = if color == qrcode::Color::Dark { &mut dark } else { &mut light };
+            let cx = x + (i % width) as f32 * cell_size;
+            let cy = y + (i / width) as f32 * cell_size;
+            pb.rect(cx, cy, cell_size, cell_size);
+        }
+        let (dark, light) = (dark.finish(), light.finish());
+        with_target(id, |target| {
+            bg.draw(target, |target, src| target.fill(&light, src, &DrawOptions::new()));
+            fg.draw(target, |target, src| target.fill(&dark, src, &DrawOptions::new()));
+            Ok(ok_response())
+        })
+    })
+}
+
+#[derive(Serialize)]
+struct Histogram {
+    r: Vec<u32>,
+    g: Vec<u32>,
+    b: Vec<u32>,
+    a: Vec<u32>,
+}
+
+fn op_dt_get_histogram(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            let mut histogram = Histogram { r: vec![0; 256], g: vec![0; 256], b: vec![0; 256], a: vec![0; 256] };
+            for &pixel in target.get_data() {
+                let [a, r, g, b] = unpremultiply(pixel);
+                histogram.r[r as usize] += 1;
+                histogram.g[g as usize] += 1;
+                histogram.b[b as usize] += 1;
+                histogram.a[a as usize] += 1;
+            }
+            Ok(value_response(histogram))
+        })
+    })
+}
+
+fn op_dt_auto_levels(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            // Fully transparent pixels carry no colour and are left out of the range.
+            let mut min = [255u8; 3];
+            let mut max = [0u8; 3];
+            for &pixel in target.get_data() {
+                let [a, r, g, b] = unpremultiply(pixel);
+                if a == 0 {
+                    continue;
+                }
+                for (c, v) in [r, g, b].iter().enumerate() {
+                    min[c] = min[c].min(*v);
+                    max[c] = max[c].max(*v);
+                }
+            }
+            let mut lut = [[0u8; 256]; 3];
+            for (c, table) in lut.iter_mut().enumerate() {
+                let range = max[c].saturating_sub(min[c]) as f32;
+                for (v, out) in table.iter_mut().enumerate() {
+                    *out = if max[c] <= min[c] {
+                        v as u8
+                    } else {
+                        ((v as f32 - min[c] as f32) * 255.0 / range).round().max(0.0).min(255.0) as u8
+                    };
+                }
+            }
+            for pixel in target.get_data_mut().iter_mut() {
+                let [a, r, g, b] = unpremultiply(*pixel);
+                *pixel = premultiply([a, lut[0][r as usize], lut[1][g as usize], lut[2][b as usize]]);
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+#[derive(Serialize)]
+struct AverageColor {
+    r: f32,
+    g: f32,
+    b: f32,
+    a: f32,
+}
+
+fn op_dt_get_average_color(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let rect = if _args.len() > 1 {
+            Some((get_arg_i32(_args, 1)?, get_arg_i32(_args, 2)?, get_arg_i32(_args, 3)?, get_arg_i32(_args, 4)?))
+        } else {
+            None
+        };
+        with_target(id, |target| {
+            let (width, height) = (target.width(), target.height());
+            let (x0, y0, x1, y1) = match rect {
+                Some((x, y, w, h)) => (x.max(0), y.max(0), x.saturating_add(w).min(width), y.saturating_add(h).min(height)),
+                None => (0, 0, width, height),
+            };
+            if x0 >= x1 || y0 >= y1 {
+                return Err(PluginError::Invalid("region does not overlap the target".to_string()));
+            }
+            let data = target.get_data();
+            let mut sum = [0u64; 4];
+            for y in y0..y1 {
+                for &pixel in &data[(y * width + x0) as usize..(y * width + x1) as usize] {
+                    let [a, r, g, b] = unpremultiply(pixel);
+                    sum[0] += r as u64;
+                    sum[1] += g as u64;
+                    sum[2] += b as u64;
+                    sum[3] += a as u64;
+                }
+            }
+            let count = ((x1 - x0) as u64 * (y1 - y0) as u64) as f64;
+            Ok(value_response(AverageColor {
+                r: (sum[0] as f64 / count) as f32,
+                g: (sum[1] as f64 / count) as f32,
+                b: (sum[2] as f64 / count) as f32,
+                a: (sum[3] as f64 / count) as f32,
+            }))
+        })
+    })
+}
+
+#[derive(Serialize)]
+struct DominantColor {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+fn op_dt_get_dominant_color(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let bits = get_arg_u8(_args, 1)?;
+        if !(1..=8).contains(&bits) {
+            return Err(PluginError::InvalidArg(1, "quantize bits must be between 1 and 8".to_string()));
+        }
+        let shift = 8 - bits;
+        with_target(id, |target| {
+            let mut buckets: HashMap<(u8, u8, u8), u32> = HashMap::new();
+            for &pixel in target.get_data() {
+                let [a, r, g, b] = unpremultiply(pixel);
+                if a == 0 {
+                    continue;
+                }
+                *buckets.entry((r >> shift, g >> shift, b >> shift)).or_insert(0) += 1;
+            }
+            // Ties go to the larger key so the result doesn't depend on the
+            // map's iteration order. The bucket is returned as its colour with
+            // the quantized-away low bits zeroed.
+            let (r, g, b) = buckets
+                .into_iter()
+                .max_by_key(|&(key, count)| (count, key))
+                .map(|(key, _)| key)
+                .ok_or_else(|| PluginError::Invalid("target is fully transparent".to_string()))?;
+            Ok(value_response(DominantColor { r: r << shift, g: g << shift, b: b << shift }))
+        })
+    })
+}
+
+// Pixels join the fill when their un-premultiplied colour lies within
+// `tolerance` of the seed's by Euclidean distance in RGB space. Fully
+// transparent pixels only match a transparent seed, so transparent areas are
+// not mistaken for black ones.
+fn op_dt_scan_flood_fill(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let seed_x = get_arg_i32(_args, 1)?;
+        let seed_y = get_arg_i32(_args, 2)?;
+        let tolerance = get_arg_u8(_args, 3)? as i32;
+        let src = get_arg_src(_args, 4, 5)?;
+        with_target(id, |target| {
+            let (width, height) = (target.width(), target.height());
+            if seed_x < 0 || seed_y < 0 || seed_x >= width || seed_y >= height {
+                return Err(PluginError::Invalid(format!("seed ({}, {}) is outside the target", seed_x, seed_y)));
+            }
+            let (w, h) = (width as usize, height as usize);
+            let data = target.get_data();
+            let seed = unpremultiply(data[seed_y as usize * w + seed_x as usize]);
+            let matches = |pixel: u32| {
+                let c = unpremultiply(pixel);
+                let dist: i32 = (1..4).map(|i| (c[i] as i32 - seed[i] as i32).pow(2)).sum();
+                (c[0] == 0) == (seed[0] == 0) && dist <= tolerance * tolerance
+            };
+            let mut filled = vec![0u8; w * h];
+            let mut stack = vec![(seed_x as usize, seed_y as usize)];
+            while let Some((x, y)) = stack.pop() {
+                let row = y * w;
+                if filled[row + x] != 0 || !matches(data[row + x]) {
+                    continue;
+                }
+                let mut left = x;
+                while left > 0 && filled[row + left - 1] == 0 && matches(data[row + left - 1]) {
+                    left -= 1;
+                }
+                let mut right = x;
+                while right + 1 < w && filled[row + right + 1] == 0 && matches(data[row + right + 1]) {
+                    right += 1;
+                }
+                for f in &mut filled[row + left..=row + right] {
+                    *f = 255;
+                }
+                // Seed one pixel per matching run in the rows above and below.
+                for ny in [y.wrapping_sub(1), y + 1].iter().copied().filter(|&ny| ny < h) {
+                    let mut in_run = false;
+                    for nx in left..=right {
+                        let i = ny * w + nx;
+                        let open = filled[i] == 0 && matches(data[i]);
+                        if open && !in_run {
+                            stack.push((nx, ny));
+                        }
+                        in_run = open;
+                    }
+                }
+            }
+            let mask = Mask { width, height, data: filled };
+            src.draw(target, |target, src| target.mask(src, 0, 0, &mask));
+            Ok(ok_response())
+        })
+    })
+}
+
+// Bilinear sample of premultiplied pixels at a continuous position where pixel
+// centres sit on half-integers; samples outside the data are transparent.
+fn sample_bilinear(data: &[u32], width: i32, height: i32, x: f32, y: f32) -> u32 {
+    let (fx, fy) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (fx.floor() as i32, fy.floor() as i32);
+    let (tx, ty) = (fx - x0 as f32, fy - y0 as f32);
+    let get = |x: i32, y: i32| {
+        if x < 0 || y < 0 || x >= width || y >= height {
+            0
+        } else {
+            data[(y * width + x) as usize]
+        }
+    };
+    let corners = [get(x0, y0), get(x0 + 1, y0), get(x0, y0 + 1), get(x0 + 1, y0 + 1)];
+    let weights = [(1. - tx) * (1. - ty), tx * (1. - ty), (1. - tx) * ty, tx * ty];
+    let mut out = 0u32;
+    for shift in [0, 8, 16, 24].iter() {
+        let v: f32 = corners.iter().zip(weights.iter()).map(|(&c, &w)| ((c >> shift) & 0xff) as f32 * w).sum();
+        out |= (v.round().max(0.).min(255.) as u32) << shift;
+    }
+    out
+}
+
+// Row-major 3x3 homography taking the four `from` corners onto `to`, or None
+// for degenerate or non-finite quads.
+fn homography(from: &[f32; 8], to: &[f32; 8]) -> Option<[f64; 9]> {
+    if from.iter().chain(to.iter()).any(|v| !v.is_finite()) {
+        return None;
+    }
+    let mut m = [[0f64; 9]; 8];
+    for i in 0..4 {
+        let (x, y) = (from[i * 2] as f64, from[i * 2 + 1] as f64);
+        let (u, v) = (to[i * 2] as f64, to[i * 2 + 1] as f64);
+        m[i * 2] = [x, y, 1., 0., 0., 0., -u * x, -u * y, u];
+        m[i * 2 + 1] = [0., 0., 0., x, y, 1., -v * x, -v * y, v];
+    }
+    // Gaussian elimination with partial pivoting on the augmented 8x9 matrix.
+    for col in 0..8 {
+        let pivot = (col..8).max_by(|&a, &b| m[a][col].abs().partial_cmp(&m[b][col].abs()).unwrap_or(std::cmp::Ordering::Equal))?;
+        if m[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        m.swap(col, pivot);
+        for row in 0..8 {
+            if row != col {
+                let factor = m[row][col] / m[col][col];
+                let pivot_row = m[col];
+                for (a, b) in m[row].iter_mut().zip(pivot_row.iter()).skip(col) {
+                    *a -= factor * b;
+                }
+            }
+        }
+    }
+    let mut h = [1f64; 9];
+    for (i, row) in m.iter().enumerate() {
+        h[i] = row[8] / row[i];
+    }
+    Some(h)
+}
+
+fn op_dt_apply_perspective_warp(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let src_quad: [f32; 8] = get_arg_json(_args, 1)?;
+        let dst_quad: [f32; 8] = get_arg_json(_args, 2)?;
+        // Inverse mapping: each destination pixel is traced back to the source.
+        let h = homography(&dst_quad, &src_quad)
+            .ok_or_else(|| PluginError::Invalid("quadrilateral is degenerate or non-finite".to_string()))?;
+        with_target(id, |target| {
+            let (width, height) = (target.width(), target.height());
+            let original = target.get_data().to_vec();
+            for (i, pixel) in target.get_data_mut().iter_mut().enumerate() {
+                let x = (i as i32 % width) as f64 + 0.5;
+                let y = (i as i32 / width) as f64 + 0.5;
+                let w = h[6] * x + h[7] * y + h[8];
+                *pixel = if w.abs() < 1e-12 {
+                    0
+                } else {
+                    let sx = (h[0] * x + h[1] * y + h[2]) / w;
+                    let sy = (h[3] * x + h[4] * y + h[5]) / w;
+                    sample_bilinear(&original, width, height, sx as f32, sy as f32)
+                };
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_swirl(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let cx = get_arg_f32(_args, 1)?;
+        let cy = get_arg_f32(_args, 2)?;
+        let radius = get_arg_f32(_args, 3)?;
+        let max_angle = get_arg_f32(_args, 4)?;
+        if radius <= 0.0 {
+            return Err(PluginError::InvalidArg(3, "radius must be positive".to_string()));
+        }
+        with_target(id, |target| {
+            let (width, height) = (target.width(), target.height());
+            let original = target.get_data().to_vec();
+            for (i, pixel) in target.get_data_mut().iter_mut().enumerate() {
+                let dx = (i as i32 % width) as f32 + 0.5 - cx;
+                let dy = (i as i32 / width) as f32 + 0.5 - cy;
+                let amount = 1. - (dx * dx + dy * dy).sqrt() / radius;
+                if amount <= 0. {
+                    continue;
+                }
+                // Rotating back by the swirl angle finds the source pixel.
+                let (sin, cos) = (-max_angle * amount).sin_cos();
+                let sx = cx + dx * cos - dy * sin;
+                let sy = cy + dx * sin + dy * cos;
+                *pixel = sample_bilinear(&original, width, height, sx, sy);
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_wave_distortion(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let amp_x = get_arg_f32(_args, 1)?;
+        let freq_x = get_arg_f32(_args, 2)?;
+        let amp_y = get_arg_f32(_args, 3)?;
+        let freq_y = get_arg_f32(_args, 4)?;
+        let phase_x = get_arg_f32(_args, 5)?;
+        let phase_y = get_arg_f32(_args, 6)?;
+        with_target(id, |target| {
+            let (width, height) = (target.width(), target.height());
+            let original = target.get_data().to_vec();
+            for (i, pixel) in target.get_data_mut().iter_mut().enumerate() {
+                let x = (i as i32 % width) as f32 + 0.5;
+                let y = (i as i32 / width) as f32 + 0.5;
+                let sx = x + amp_x * (y * freq_x + phase_x).sin();
+                let sy = y + amp_y * (x * freq_y + phase_y).sin();
+                *pixel = sample_bilinear(&original, width, height, sx, sy);
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+// Index into the [a, r, g, b] arrays produced by unpremultiply.
+fn get_arg_channel(args: &mut [ZeroCopyBuf], idx: usize) -> PluginResult<usize> {
+    match get_arg_str(args, idx)?.as_str() {
+        "a" => Ok(0),
+        "r" => Ok(1),
+        "g" => Ok(2),
+        "b" => Ok(3),
+        other => Err(PluginError::InvalidArg(idx, format!("unknown channel `{}`", other))),
+    }
+}
+
+fn op_dt_get_channel(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let channel = get_arg_channel(_args, 1)?;
+        let dt = with_target(id, |target| {
+            let mut dt = DrawTarget::new(target.width(), target.height());
+            for (out, &pixel) in dt.get_data_mut().iter_mut().zip(target.get_data()) {
+                let v = unpremultiply(pixel)[channel];
+                *out = premultiply([255, v, v, v]);
+            }
+            Ok(dt)
+        })?;
+        Ok(value_response(insert_target_auto(dt)))
+    })
+}
+
+fn op_dt_set_channel(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let dst_id = get_arg_u32(_args, 0)?;
+        let channel = get_arg_channel(_args, 1)?;
+        let src_id = get_arg_u32(_args, 2)?;
+        let (size, luma) = with_target(src_id, |src| {
+            // Rec. 709 luma of the unpremultiplied colour.
+            let luma: Vec<u8> = src.get_data().iter().map(|&pixel| {
+                let [_, r, g, b] = unpremultiply(pixel);
+                (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32).round() as u8
+            }).collect();
+            Ok(((src.width(), src.height()), luma))
+        })?;
+        with_target(dst_id, |target| {
+            if (target.width(), target.height()) != size {
+                return Err(PluginError::Invalid(format!(
+                    "source size {}x{} does not match {}x{}",
+                    size.0, size.1, target.width(), target.height()
+                )));
+            }
+            for (pixel, &v) in target.get_data_mut().iter_mut().zip(&luma) {
+                let mut argb = unpremultiply(*pixel);
+                argb[channel] = v;
+                *pixel = premultiply(argb);
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_mask_with_target(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let base_id = get_arg_u32(_args, 0)?;
+        let mask_id = get_arg_u32(_args, 1)?;
+        let (size, alpha) = with_target(mask_id, |mask| {
+            let alpha: Vec<u32> = mask.get_data().iter().map(|&pixel| pixel >> 24).collect();
+            Ok(((mask.width(), mask.height()), alpha))
+        })?;
+        with_target(base_id, |target| {
+            if (target.width(), target.height()) != size {
+                return Err(PluginError::Invalid(format!(
+                    "mask size {}x{} does not match {}x{}",
+                    size.0, size.1, target.width(), target.height()
+                )));
+            }
+            // Scaling every premultiplied component keeps the pixel premultiplied.
+            for (pixel, &m) in target.get_data_mut().iter_mut().zip(&alpha) {
+                let mut out = 0;
+                for shift in [0, 8, 16, 24].iter() {
+                    out |= ((((*pixel >> shift) & 0xff) * m + 127) / 255) << shift;
+                }
+                *pixel = out;
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_premultiply(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            // The current data is treated as straight alpha.
+            for pixel in target.get_data_mut().iter_mut() {
+                *pixel = premultiply(pixel.to_be_bytes());
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_unpremultiply(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            // Fully transparent pixels become zero rather than dividing by zero.
+            for pixel in target.get_data_mut().iter_mut() {
+                *pixel = u32::from_be_bytes(unpremultiply(*pixel));
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+// Applies a per-component lookup table to the unpremultiplied RGB of every pixel.
+fn map_rgb(target: &mut DrawTarget, curve: impl Fn(f32) -> f32) {
+    let mut lut = [0u8; 256];
+    for (v, out) in lut.iter_mut().enumerate() {
+        *out = (curve(v as f32 / 255.) * 255.).round().max(0.).min(255.) as u8;
+    }
+    for pixel in target.get_data_mut().iter_mut() {
+        let [a, r, g, b] = unpremultiply(*pixel);
+        *pixel = premultiply([a, lut[r as usize], lut[g as usize], lut[b as usize]]);
+    }
+}
+
+fn op_dt_convert_to_linear(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            map_rgb(target, |c| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) });
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_convert_to_srgb(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |target| {
+            map_rgb(target, |c| if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1. / 2.4) - 0.055 });
+            Ok(ok_response())
+        })
+    })
+}
+
+// Resamples premultiplied pixels into a new target. Filters with negative
+// lobes can push a component above its alpha, so components are clamped.
+fn resize_target(target: &DrawTarget, width: u32, height: u32, filter: image::imageops::FilterType) -> PluginResult<DrawTarget> {
+    let mut resized = new_target(width.min(i32::MAX as u32) as i32, height.min(i32::MAX as u32) as i32)?;
+    let bytes: Vec<u8> = target.get_data().iter().flat_map(|pixel| pixel.to_be_bytes().to_vec()).collect();
+    let source = image::RgbaImage::from_raw(target.width() as u32, target.height() as u32, bytes)
+        .ok_or_else(|| PluginError::Image("invalid target size".to_string()))?;
+    let scaled = image::imageops::resize(&source, width, height, filter);
+    for (pixel, argb) in resized.get_data_mut().iter_mut().zip(scaled.as_raw().chunks_exact(4)) {
+        let a = argb[0];
+        *pixel = u32::from_be_bytes([a, argb[1].min(a), argb[2].min(a), argb[3].min(a)]);
+    }
+    Ok(resized)
+}
+
+fn op_dt_scale_lanczos(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let width = get_arg_u32(_args, 1)?;
+        let height = get_arg_u32(_args, 2)?;
+        with_target(id, |target| {
+            *target = resize_target(target, width, height, image::imageops::FilterType::Lanczos3)?;
+            // The resized target starts with empty clip and layer stacks.
+            with_depths(id, |depths| *depths = (0, 0));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_image_with_color_key(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let key = [get_arg_u8(_args, 4)? as i32, get_arg_u8(_args, 5)? as i32, get_arg_u8(_args, 6)? as i32];
+        let tolerance = get_arg_u8(_args, 7)? as i32;
+        let img = get_arg_img_with(_args, 1, |c| {
+            let dist: i32 = c.iter().zip(key.iter()).map(|(&v, &k)| (v as i32 - k).pow(2)).sum();
+            // Keyed pixels are cleared entirely; a colour left behind a zero
+            // alpha would still be added when compositing.
+            if dist <= tolerance * tolerance {
+                c.copy_from_slice(&[0, 0, 0, 0]);
+            }
+        })?;
+        with_target(id, |target| {
+            target.draw_image_at(x, y, &Image {
+                width: img.width as i32,
+                height: img.height as i32,
+                data: &*img.data
+            }, &DrawOptions::new());
+            Ok(ok_response())
+        })
+    })
+}
+
+#[derive(Deserialize)]
+struct JsonInsets {
+    top: u32,
+    right: u32,
+    bottom: u32,
+    left: u32,
+}
+
+fn op_dt_nine_slice_fill(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let insets: JsonInsets = get_arg_json(_args, 2)?;
+        let x = get_arg_f32(_args, 3)?;
+        let y = get_arg_f32(_args, 4)?;
+        let w = get_arg_f32(_args, 5)?;
+        let h = get_arg_f32(_args, 6)?;
+        let img = get_arg_img(_args, 1)?;
+        if insets.left.saturating_add(insets.right) > img.width || insets.top.saturating_add(insets.bottom) > img.height {
+            return Err(PluginError::InvalidArg(2, "insets are larger than the image".to_string()));
+        }
+        // Source and destination edges of the three columns and rows.
+        let src_x = [0, insets.left, img.width - insets.right, img.width];
+        let src_y = [0, insets.top, img.height - insets.bottom, img.height];
+        let mid_w = (w - (insets.left + insets.right) as f32).max(0.);
+        let mid_h = (h - (insets.top + insets.bottom) as f32).max(0.);
+        let dst_x = [x, x + insets.left as f32, x + insets.left as f32 + mid_w, x + insets.left as f32 + mid_w + insets.right as f32];
+        let dst_y = [y, y + insets.top as f32, y + insets.top as f32 + mid_h, y + insets.top as f32 + mid_h + insets.bottom as f32];
+        with_target(id, |target| {
+            for row in 0..3 {
+                for col in 0..3 {
+                    let (sw, sh) = (src_x[col + 1] - src_x[col], src_y[row + 1] - src_y[row]);
+                    let (dw, dh) = (dst_x[col + 1] - dst_x[col], dst_y[row + 1] - dst_y[row]);
+                    if sw == 0 || sh == 0 || dw <= 0. || dh <= 0. {
+                        continue;
+                    }
+                    // Each slice is copied out so the filter cannot sample its
+                    // neighbours.
+                    let mut data = Vec::with_capacity((sw * sh) as usize);
+                    for sy in src_y[row]..src_y[row + 1] {
+                        let start = (sy * img.width + src_x[col]) as usize;
+                        data.extend_from_slice(&img.data[start..start + sw as usize]);
+                    }
+                    let slice = Image { width: sw as i32, height: sh as i32, data: &data };
+                    target.draw_image_with_size_at(dst_x[col], dst_y[row], dw, dh, &slice, &DrawOptions::new());
+                }
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_checkerboard(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 1)?;
+        let y = get_arg_f32(_args, 2)?;
+        let w = get_arg_f32(_args, 3)?;
+        let h = get_arg_f32(_args, 4)?;
+        let cell = get_arg_u32(_args, 5)?;
+        let color_a = color_from_json(get_arg_json(_args, 6)?);
+        let color_b = color_from_json(get_arg_json(_args, 7)?);
+        if cell == 0 {
+            return Err(PluginError::InvalidArg(5, "cell size must be positive".to_string()));
+        }
+        let cell = cell as f32;
+        let (cols, rows) = ((w / cell).ceil().max(0.) as u32, (h / cell).ceil().max(0.) as u32);
+        // Cells of each colour share a path; the last row and column are cut
+        // to the rectangle.
+        let mut paths = [PathBuilder::new(), PathBuilder::new()];
+        for row in 0..rows {
+            for col in 0..cols {
+                let (cx, cy) = (col as f32 * cell, row as f32 * cell);
+                paths[((row + col) % 2) as usize].rect(x + cx, y + cy, cell.min(w - cx), cell.min(h - cy));
+            }
+        }
+        let [a, b] = paths;
+        with_target(id, |target| {
+            target.fill(&a.finish(), &Source::Solid(SolidSource::from(color_a)), &DrawOptions::new());
+            target.fill(&b.finish(), &Source::Solid(SolidSource::from(color_b)), &DrawOptions::new());
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_grid(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 1)?;
+        let y = get_arg_f32(_args, 2)?;
+        let w = get_arg_f32(_args, 3)?;
+        let h = get_arg_f32(_args, 4)?;
+        let cols = get_arg_u32(_args, 5)?;
+        let rows = get_arg_u32(_args, 6)?;
+        let src = get_arg_src(_args, 7, 9)?;
+        let stroke = stroke_style_from_json(get_arg_json(_args, 8)?);
+        if cols == 0 || rows == 0 {
+            return Err(PluginError::Invalid("grid needs at least one column and row".to_string()));
+        }
+        // Every line goes into one path so the grid is stroked in a single call.
+        let mut pb = PathBuilder::new();
+        for col in 0..=cols {
+            let lx = x + w * col as f32 / cols as f32;
+            pb.move_to(lx, y);
+            pb.line_to(lx, y + h);
+        }
+        for row in 0..=rows {
+            let ly = y + h * row as f32 / rows as f32;
+            pb.move_to(x, ly);
+            pb.line_to(x + w, ly);
+        }
+        let path = pb.finish();
+        with_target(id, |target| {
+            src.draw(target, |target, src| target.stroke(&path, src, &stroke, &DrawOptions::new()));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_snapshot(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let snapshot_id = get_arg_u32(_args, 1)?;
+        let snapshot = with_target(id, |target| {
+            Ok((target.width() as u32, target.height() as u32, Arc::new(Box::from(target.get_data()))))
+        })?;
+        SNAPSHOTS.with(|map| map.borrow_mut().insert(snapshot_id, snapshot));
+        Ok(ok_response())
+    })
+}
+
+fn op_dt_draw_snapshot_at(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let snapshot_id = get_arg_u32(_args, 1)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let (width, height, data) = SNAPSHOTS.with(|map| {
+            map.borrow().get(&snapshot_id).cloned().ok_or(PluginError::SnapshotNotFound(snapshot_id))
+        })?;
+        with_target(id, |target| {
+            target.draw_image_at(x, y, &Image {
+                width: width as i32,
+                height: height as i32,
+                data: &data
+            }, &DrawOptions::new());
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_snapshot_destroy(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        SNAPSHOTS.with(|map| {
+            map.borrow_mut().remove(&id).ok_or(PluginError::SnapshotNotFound(id))?;
+            Ok(ok_response())
+        })
+    })
+}
+
+// Separable Gaussian blur of premultiplied pixels; everything outside the
+// data counts as transparent.
+fn gaussian_blur(data: &mut [u32], width: usize, height: usize, sigma: f32) {
+    if sigma <= 0. {
+        return;
+    }
+    let radius = (sigma * 3.).ceil() as isize;
+    let mut kernel: Vec<f32> = (-radius..=radius).map(|i| (-((i * i) as f32) / (2. * sigma * sigma)).exp()).collect();
+    let total: f32 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|k| *k /= total);
+    let pass = |src: &[u32], dst: &mut [u32], horizontal: bool| {
+        for y in 0..height {
+            for x in 0..width {
+                let mut acc = [0f32; 4];
+                for (k, &weight) in kernel.iter().enumerate() {
+                    let offset = k as isize - radius;
+                    let (sx, sy) = if horizontal { (x as isize + offset, y as isize) } else { (x as isize, y as isize + offset) };
+                    if sx < 0 || sy < 0 || sx >= width as isize || sy >= height as isize {
+                        continue;
+                    }
+                    let pixel = src[sy as usize * width + sx as usize];
+                    for (c, a) in acc.iter_mut().enumerate() {
+                        *a += ((pixel >> (c * 8)) & 0xff) as f32 * weight;
+                    }
+                }
+                dst[y * width + x] = acc.iter().enumerate()
+                    .fold(0, |out, (c, v)| out | ((v.round().min(255.) as u32) << (c * 8)));
+            }
+        }
+    };
+    let mut tmp = vec![0u32; data.len()];
+    pass(data, &mut tmp, true);
+    pass(&tmp, data, false);
+}
+
+fn op_dt_render_shadow_map(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let path = get_arg_path(_args, 0)?;
+        let sigma = get_arg_f32(_args, 1)?;
+        let width = get_arg_u32(_args, 2)?;
+        let height = get_arg_u32(_args, 3)?;
+        let dst_id = get_arg_u32(_args, 4)?;
+        if sigma < 0. {
+            return Err(PluginError::InvalidArg(1, "blur sigma must not be negative".to_string()));
+        }
+        if TARGETS.with(|map| map.borrow().contains_key(&dst_id)) {
+            return Err(PluginError::TargetExists(dst_id));
+        }
+        let mut dt = new_target(width.min(i32::MAX as u32) as i32, height.min(i32::MAX as u32) as i32)?;
+        dt.fill(&path, &Source::Solid(SolidSource::from(Color::new(255, 0, 0, 0))), &DrawOptions::new());
+        gaussian_blur(dt.get_data_mut(), width as usize, height as usize, sigma);
+        TARGETS.with(|map| map.borrow_mut().insert(dst_id, dt));
+        Ok(ok_response())
+    })
+}
+
+#[derive(Serialize)]
+struct MipLevel {
+    id: u32,
+    width: u32,
+    height: u32,
+}
+
+fn op_dt_generate_mipmap_chain(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let levels = with_target(id, |target| {
+            // floor(log2(min(w, h)))
+            let count = 31 - (target.width().min(target.height()) as u32).leading_zeros();
+            let mut levels: Vec<DrawTarget> = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                // Each level halves the previous one rather than the original.
+                let prev = levels.last().unwrap_or(target);
+                let (w, h) = ((prev.width() as u32 / 2).max(1), (prev.height() as u32 / 2).max(1));
+                let level = resize_target(prev, w, h, image::imageops::FilterType::Triangle)?;
+                levels.push(level);
+            }
+            Ok(levels)
+        })?;
+        let chain: Vec<MipLevel> = levels.into_iter().map(|level| {
+            let (width, height) = (level.width() as u32, level.height() as u32);
+            MipLevel { id: insert_target_auto(level), width, height }
+        }).collect();
+        Ok(value_response(chain))
+    })
+}
+
+fn op_dt_draw_mipmap_at(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let chain: Vec<u32> = get_arg_json(_args, 0)?;
+        let id = get_arg_u32(_args, 1)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let w = get_arg_f32(_args, 4)?;
+        let h = get_arg_f32(_args, 5)?;
+        if w <= 0. || h <= 0. {
+            return Err(PluginError::Invalid("destination size must be positive".to_string()));
+        }
+        // The closest level is the one whose scale factor to the destination
+        // is nearest to 1 on a log scale.
+        let mut best: Option<(f32, u32)> = None;
+        for &level in &chain {
+            let distance = with_target(level, |target| Ok((target.width() as f32 / w).log2().abs()))?;
+            if best.map_or(true, |(d, _)| distance < d) {
+                best = Some((distance, level));
+            }
+        }
+        let (_, level) = best.ok_or_else(|| PluginError::InvalidArg(0, "mipmap chain is empty".to_string()))?;
+        let (width, height, data) = with_target(level, |target| {
+            Ok((target.width(), target.height(), target.get_data().to_vec()))
+        })?;
+        with_target(id, |target| {
+            target.draw_image_with_size_at(x, y, w, h, &Image { width, height, data: &data }, &DrawOptions::new());
+            Ok(ok_response())
+        })
+    })
+}
+
+// Appends an SVG elliptical arc as cubic Béziers, following the endpoint to
+// centre conversion in the SVG implementation notes (F.6.5).
+#[allow(clippy::too_many_arguments)]
+fn svg_arc_to(pb: &mut PathBuilder, from: (f64, f64), rx: f64, ry: f64, rotation: f64, large_arc: bool, sweep: bool, to: (f64, f64)) {
+    let (mut rx, mut ry) = (rx.abs(), ry.abs());
+    if rx == 0. || ry == 0. || from == to {
+        pb.line_to(to.0 as f32, to.1 as f32);
+        return;
+    }
+    let (sin, cos) = rotation.to_radians().sin_cos();
+    let (dx, dy) = ((from.0 - to.0) / 2., (from.1 - to.1) / 2.);
+    let (x1, y1) = (cos * dx + sin * dy, -sin * dx + cos * dy);
+    // Radii too small to reach the endpoint are scaled up.
+    let lambda = (x1 * x1) / (rx * rx) + (y1 * y1) / (ry * ry);
+    if lambda > 1. {
+        rx *= lambda.sqrt();
+        ry *= lambda.sqrt();
+    }
+    let num = rx * rx * ry * ry - rx * rx * y1 * y1 - ry * ry * x1 * x1;
+    let den = rx * rx * y1 * y1 + ry * ry * x1 * x1;
+    let mut coef = (num / den).max(0.).sqrt();
+    if large_arc == sweep {
+        coef = -coef;
+    }
+    let (cx1, cy1) = (coef * rx * y1 / ry, -coef * ry * x1 / rx);
+    let cx = cos * cx1 - sin * cy1 + (from.0 + to.0) / 2.;
+    let cy = sin * cx1 + cos * cy1 + (from.1 + to.1) / 2.;
+    let angle = |ux: f64, uy: f64| uy.atan2(ux);
+    let start = angle((x1 - cx1) / rx, (y1 - cy1) / ry);
+    let mut delta = angle((-x1 - cx1) / rx, (-y1 - cy1) / ry) - start;
+    if sweep && delta < 0. {
+        delta += 2. * std::f64::consts::PI;
+    } else if !sweep && delta > 0. {
+        delta -= 2. * std::f64::consts::PI;
+    }
+    // One cubic per quarter turn or less.
+    let segments = (delta.abs() / std::f64::consts::FRAC_PI_2).ceil().max(1.) as usize;
+    let step = delta / segments as f64;
+    let k = 4. / 3. * (step / 4.).tan();
+    let point = |t: f64, dx: f64, dy: f64| {
+        let (x, y) = (rx * (t.cos() + dx), ry * (t.sin() + dy));
+        ((cos * x - sin * y + cx) as f32, (sin * x + cos * y + cy) as f32)
+    };
+    for i in 0..segments {
+        let (t0, t1) = (start + step * i as f64, start + step * (i + 1) as f64);
+        let c1 = point(t0, -k * t0.sin(), k * t0.cos());
+        let c2 = point(t1, k * t1.sin(), -k * t1.cos());
+        let end = if i + 1 == segments { (to.0 as f32, to.1 as f32) } else { point(t1, 0., 0.) };
+        pb.cubic_to(c1.0, c1.1, c2.0, c2.1, end.0, end.1);
+    }
+}
+
+// Builds a path from an SVG `d` attribute; relative and shorthand commands
+// are resolved to absolute ones.
+fn path_from_svg(d: &str) -> PluginResult<Path> {
+    use svgtypes::PathSegment as S;
+    let mut pb = PathBuilder::new();
+    let (mut cur, mut start) = ((0f64, 0f64), (0f64, 0f64));
+    // Reflected control points for S/s and T/t.
+    let (mut last_cubic, mut last_quad): (Option<(f64, f64)>, Option<(f64, f64)>) = (None, None);
+    for segment in svgtypes::PathParser::from(d) {
+        let segment = segment.map_err(|e| PluginError::Invalid(format!("invalid svg path: {}", e)))?;
+        let abs = match segment {
+            S::MoveTo { abs, .. } | S::LineTo { abs, .. } | S::HorizontalLineTo { abs, .. }
+            | S::VerticalLineTo { abs, .. } | S::CurveTo { abs, .. } | S::SmoothCurveTo { abs, .. }
+            | S::Quadratic { abs, .. } | S::SmoothQuadratic { abs, .. } | S::EllipticalArc { abs, .. }
+            | S::ClosePath { abs } => abs,
+        };
+        let p = |x: f64, y: f64| if abs { (x, y) } else { (cur.0 + x, cur.1 + y) };
+        let reflect = |c: Option<(f64, f64)>| c.map_or(cur, |c| (2. * cur.0 - c.0, 2. * cur.1 - c.1));
+        let (mut cubic, mut quad) = (None, None);
+        match segment {
+            S::MoveTo { x, y, .. } => {
+                cur = p(x, y);
+                start = cur;
+                pb.move_to(cur.0 as f32, cur.1 as f32);
+            }
+            S::LineTo { x, y, .. } => {
+                cur = p(x, y);
+                pb.line_to(cur.0 as f32, cur.1 as f32);
+            }
+            S::HorizontalLineTo { x, .. } => {
+                cur.0 = if abs { x } else { cur.0 + x };
+                pb.line_to(cur.0 as f32, cur.1 as f32);
+            }
+            S::VerticalLineTo { y, .. } => {
+                cur.1 = if abs { y } else { cur.1 + y };
+                pb.line_to(cur.0 as f32, cur.1 as f32);
+            }
+            S::CurveTo { x1, y1, x2, y2, x, y, .. } => {
+                let (c1, c2, end) = (p(x1, y1), p(x2, y2), p(x, y));
+                pb.cubic_to(c1.0 as f32, c1.1 as f32, c2.0 as f32, c2.1 as f32, end.0 as f32, end.1 as f32);
+                cubic = Some(c2);
+                cur = end;
+            }
+            S::SmoothCurveTo { x2, y2, x, y, .. } => {
+                let (c1, c2, end) = (reflect(last_cubic), p(x2, y2), p(x, y));
+                pb.cubic_to(c1.0 as f32, c1.1 as f32, c2.0 as f32, c2.1 as f32, end.0 as f32, end.1 as f32);
+                cubic = Some(c2);
+                cur = end;
+            }
+            S::Quadratic { x1, y1, x, y, .. } => {
+                let (c, end) = (p(x1, y1), p(x, y));
+                pb.quad_to(c.0 as f32, c.1 as f32, end.0 as f32, end.1 as f32);
+                quad = Some(c);
+                cur = end;
+            }
+            S::SmoothQuadratic { x, y, .. } => {
+                let (c, end) = (reflect(last_quad), p(x, y));
+                pb.quad_to(c.0 as f32, c.1 as f32, end.0 as f32, end.1 as f32);
+                quad = Some(c);
+                cur = end;
+            }
+            S::EllipticalArc { rx, ry, x_axis_rotation, large_arc, sweep, x, y, .. } => {
+                let end = p(x, y);
+                svg_arc_to(&mut pb, cur, rx, ry, x_axis_rotation, large_arc, sweep, end);
+                cur = end;
+            }
+            S::ClosePath { .. } => {
+                pb.close();
+                cur = start;
+            }
+        }
+        last_cubic = cubic;
+        last_quad = quad;
+    }
+    Ok(pb.finish())
+}
+
+fn op_dt_svg_path_as_clip_region(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let path = path_from_svg(&get_arg_str(_args, 1)?)?;
+        with_target(id, |target| {
+            target.push_clip(&path);
+            with_depths(id, |depths| depths.0 += 1);
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_path_apply_transform(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let path = get_arg_path(_args, 0)?;
+        let matrix: [f32; 6] = get_arg_json(_args, 1)?;
+        // Arcs are already flattened to cubics in the built path, so the
+        // result stays exact under non-uniform scales and skews.
+        let path = path.transform(&transform_from_json(matrix));
+        Ok(value_response(path_to_json(&path)))
+    })
+}
+
+// Reverses every subpath and their order. A segment's control points are
+// swapped and it ends at the point it used to start from.
+fn reverse_path(path: &Path) -> Path {
+    // (start, segments, closed) for each subpath.
+    let mut subpaths: Vec<(Point, Vec<PathOp>, bool)> = Vec::new();
+    let mut start = Point::new(0., 0.);
+    for op in path.ops.iter() {
+        match *op {
+            PathOp::MoveTo(p) => {
+                start = p;
+                subpaths.push((p, Vec::new(), false));
+            }
+            PathOp::Close => {
+                if let Some(subpath) = subpaths.last_mut() {
+                    subpath.2 = true;
+                }
+            }
+            segment => {
+                // Drawing on after a close starts a new subpath at the old start.
+                if subpaths.last().map_or(true, |subpath| subpath.2) {
+                    subpaths.push((start, Vec::new(), false));
+                }
+                subpaths.last_mut().unwrap().1.push(segment);
+            }
+        }
+    }
+    let end = |op: &PathOp, from: Point| match *op {
+        PathOp::LineTo(p) | PathOp::QuadTo(_, p) | PathOp::CubicTo(_, _, p) | PathOp::MoveTo(p) => p,
+        PathOp::Close => from,
+    };
+    let mut ops = Vec::with_capacity(path.ops.len());
+    for (start, segments, closed) in subpaths.into_iter().rev() {
+        let mut points = vec![start];
+        for segment in segments.iter() {
+            let last = *points.last().unwrap();
+            points.push(end(segment, last));
+        }
+        ops.push(PathOp::MoveTo(*points.last().unwrap()));
+        for (segment, &to) in segments.iter().zip(points.iter()).rev() {
+            ops.push(match *segment {
+                PathOp::QuadTo(c, _) => PathOp::QuadTo(c, to),
+                PathOp::CubicTo(c1, c2, _) => PathOp::CubicTo(c2, c1, to),
+                _ => PathOp::LineTo(to),
+            });
+        }
+        if closed {
+            ops.push(PathOp::Close);
+        }
+    }
+    Path { ops, winding: path.winding }
+}
+
+fn op_path_reverse(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let path = get_arg_path(_args, 0)?;
+        Ok(value_response(path_to_json(&reverse_path(&path))))
+    })
+}
+
+// Offsets a closed polygon outwards by `distance` (inwards when negative).
+// Corners are mitred, falling back to a bevel once the mitre would exceed
+// four times the distance.
+fn offset_polygon(points: &[Point], distance: f32) -> Vec<Point> {
+    let n = points.len();
+    let area: f32 = (0..n).map(|i| {
+        let (a, b) = (points[i], points[(i + 1) % n]);
+        a.x * b.y - b.x * a.y
+    }).sum();
+    // Outward is to the right of each edge for a positive shoelace area.
+    let side = if area < 0. { -distance } else { distance };
+    let normal = |a: Point, b: Point| {
+        let d = b - a;
+        let len = d.length();
+        euclid::Vector2D::new(d.y / len, -d.x / len)
+    };
+    let mut out = Vec::with_capacity(n * 2);
+    for i in 0..n {
+        let (prev, p, next) = (points[(i + n - 1) % n], points[i], points[(i + 1) % n]);
+        let (n1, n2) = (normal(prev, p), normal(p, next));
+        let denom = 1. + n1.dot(n2);
+        let miter = (n1 + n2) * (side / denom.max(1e-6));
+        if denom > 1e-3 && miter.length() <= 4. * distance.abs() {
+            out.push(p + miter);
+        } else {
+            out.push(p + n1 * side);
+            out.push(p + n2 * side);
+        }
+    }
+    out
+}
+
+fn op_path_offset(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let path = get_arg_path(_args, 0)?;
+        let distance = get_arg_f32(_args, 1)?;
+        let mut pb = PathBuilder::new();
+        for (mut points, _) in flatten_path(&path, PathMeasure::CURVE_STEPS) {
+            // Every subpath is treated as closed, as it would be when filled.
+            points.dedup();
+            if points.len() > 1 && points.first() == points.last() {
+                points.pop();
+            }
+            if points.len() < 3 {
+                continue;
+            }
+            let offset = offset_polygon(&points, distance);
+            pb.move_to(offset[0].x, offset[0].y);
+            for p in &offset[1..] {
+                pb.line_to(p.x, p.y);
+            }
+            pb.close();
+        }
+        Ok(value_response(path_to_json(&pb.finish())))
+    })
+}
+
+fn op_dt_fill_gradient_rect(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 1)?;
+        let y = get_arg_f32(_args, 2)?;
+        let w = get_arg_f32(_args, 3)?;
+        let h = get_arg_f32(_args, 4)?;
+        let src = get_arg_src(_args, 5, 6)?;
+        let mut pb = PathBuilder::new();
+        pb.rect(x, y, w, h);
+        let path = pb.finish();
+        with_target(id, |target| {
+            src.draw(target, |target, src| target.fill(&path, src, &DrawOptions::new()));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_draw_image_at_subpixel(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 2)?;
+        let y = get_arg_f32(_args, 3)?;
+        let img = get_arg_img(_args, 1)?;
+        with_target(id, |target| {
+            // The fractional part is moved into the transform so the bilinear
+            // filter blends the image across pixel boundaries.
+            let original = *target.get_transform();
+            target.set_transform(&original.pre_translate(euclid::vec2(x.fract(), y.fract())));
+            target.draw_image_at(x.trunc(), y.trunc(), &Image {
+                width: img.width as i32,
+                height: img.height as i32,
+                data: &*img.data
+            }, &DrawOptions::new());
+            target.set_transform(&original);
+            Ok(ok_response())
+        })
+    })
+}
+
+// Runs oxipng over a normal PNG encode on the calling thread. This is much
+// slower than op_dt_encode, increasingly so at higher levels.
+fn op_dt_encode_png_optimized(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let level = get_arg_u8(_args, 1)?;
+        if level > 6 {
+            return Err(PluginError::InvalidArg(1, "optimization level must be within [0, 6]".to_string()));
+        }
+        let png = with_target(id, |target| {
+            encode_png(target.width() as u32, target.height() as u32, png::ColorType::RGBA, &target_rgba(target), png::Compression::Default)
+        })?;
+        let buf = oxipng::optimize_from_memory(&png, &oxipng::Options::from_preset(level))
+            .map_err(|e| PluginError::Image(e.to_string()))?;
+        Ok(Op::Sync(buf.into_boxed_slice()))
+    })
+}
+
+fn op_dt_canvas_to_data_url(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let format = get_arg_str(_args, 1)?;
+        // Quality is in [0, 1] as with canvas.toDataURL.
+        let quality = get_arg_f32_or(_args, 2, 0.92)?.max(0.).min(1.);
+        let (mime, buf) = with_target(id, |target| {
+            let (width, height) = (target.width() as u32, target.height() as u32);
+            match format.as_str() {
+                "png" => Ok(("image/png", encode_png(width, height, png::ColorType::RGBA, &target_rgba(target), png::Compression::Default)?)),
+                "jpeg" => {
+                    // Premultiplied colour is the image composited over black.
+                    let mut rgb = Vec::with_capacity(target.get_data().len() * 3);
+                    for &pixel in target.get_data() {
+                        rgb.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+                    }
+                    let mut buf = Vec::new();
+                    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buf, (quality * 100.).round().max(1.) as u8)
+                        .encode(&rgb, width, height, image::ColorType::Rgb8)?;
+                    Ok(("image/jpeg", buf))
+                }
+                #[cfg(feature = "webp")]
+                "webp" => {
+                    let rgba = target_rgba(target);
+                    Ok(("image/webp", webp::Encoder::from_rgba(&rgba, width, height).encode(quality * 100.).to_vec()))
+                }
+                #[cfg(not(feature = "webp"))]
+                "webp" => Err(PluginError::InvalidArg(1, "webp requires building with the `webp` feature".to_string())),
+                other => Err(PluginError::InvalidArg(1, format!("unsupported format `{}`", other))),
+            }
+        })?;
+        Ok(value_response(format!("data:{};base64,{}", mime, base64::encode(&buf))))
+    })
+}
+
+// Rectangle with corners rounded by `radius`, clamped to half the shorter side.
+fn rounded_rect(x: f32, y: f32, w: f32, h: f32, radius: f32) -> Path {
+    let r = radius.max(0.).min(w.abs().min(h.abs()) / 2.);
+    let mut pb = PathBuilder::new();
+    if r == 0. {
+        pb.rect(x, y, w, h);
+        return pb.finish();
+    }
+    // Distance of the cubic control points from each corner's end points.
+    let k = r * (1. - 0.552_284_8);
+    let (r2, b) = (x + w, y + h);
+    pb.move_to(x + r, y);
+    pb.line_to(r2 - r, y);
+    pb.cubic_to(r2 - k, y, r2, y + k, r2, y + r);
+    pb.line_to(r2, b - r);
+    pb.cubic_to(r2, b - k, r2 - k, b, r2 - r, b);
+    pb.line_to(x + r, b);
+    pb.cubic_to(x + k, b, x, b - k, x, b - r);
+    pb.line_to(x, y + r);
+    pb.cubic_to(x, y + k, x + k, y, x + r, y);
+    pb.close();
+    pb.finish()
+}
+
+fn op_dt_draw_progress_bar(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 1)?;
+        let y = get_arg_f32(_args, 2)?;
+        let w = get_arg_f32(_args, 3)?;
+        let h = get_arg_f32(_args, 4)?;
+        let progress = get_arg_f32(_args, 5)?.max(0.).min(1.);
+        // Pattern images follow all fixed args: the background's at 9 and the
+        // fill's at 10.
+        let bg = get_arg_src(_args, 6, 9)?;
+        let fill = get_arg_src(_args, 7, 10)?;
+        let radius = get_arg_f32(_args, 8)?;
+        let track = rounded_rect(x, y, w, h, radius);
+        let bar = rounded_rect(x, y, w * progress, h, radius);
+        with_target(id, |target| {
+            bg.draw(target, |target, src| target.fill(&track, src, &DrawOptions::new()));
+            // Clipping to the track keeps a short bar inside the rounded ends.
+            target.push_clip(&track);
+            fill.draw(target, |target, src| target.fill(&bar, src, &DrawOptions::new()));
+            target.pop_clip();
+            Ok(ok_response())
+        })
+    })
+}
+
+#[derive(Deserialize)]
+struct JsonImageDraw {
+    image_idx: u32,
+    x: f32,
+    y: f32,
+    w: Option<f32>,
+    h: Option<f32>,
+    options: Option<JsonDrawOptions>,
+}
+
+fn op_dt_draw_image_batch(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let draws: Vec<JsonImageDraw> = get_arg_json(_args, 1)?;
+        // Every image buffer is decoded once, however many draws use it.
+        let mut images: HashMap<u32, JsonImage> = HashMap::new();
+        for draw in draws.iter() {
+            if draw.image_idx < 2 {
+                return Err(PluginError::InvalidArg(1, format!("image_idx {} is not an image argument", draw.image_idx)));
+            }
+            if let std::collections::hash_map::Entry::Vacant(entry) = images.entry(draw.image_idx) {
+                entry.insert(get_arg_img(_args, draw.image_idx as usize)?);
+            }
+        }
+        with_target(id, |target| {
+            for draw in draws {
+                let img = &images[&draw.image_idx];
+                let image = Image { width: img.width as i32, height: img.height as i32, data: &*img.data };
+                let options = draw.options.map_or_else(DrawOptions::new, draw_options_from_json);
+                let w = draw.w.unwrap_or(img.width as f32);
+                let h = draw.h.unwrap_or(img.height as f32);
+                target.draw_image_with_size_at(draw.x, draw.y, w, h, &image, &options);
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_blend_mode_fill_rect(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let x = get_arg_f32(_args, 1)?;
+        let y = get_arg_f32(_args, 2)?;
+        let w = get_arg_f32(_args, 3)?;
+        let h = get_arg_f32(_args, 4)?;
+        let blend: JsonBlendMode = get_arg_json(_args, 5)?;
+        let src = get_arg_src(_args, 6, 7)?;
+        let mut options = DrawOptions::new();
+        options.blend_mode = blend_from_json(blend);
+        with_target(id, |target| {
+            src.draw(target, |target, src| target.fill_rect(x, y, w, h, src, &options));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_find_edges(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let threshold = get_arg_u8(_args, 1)? as f32;
+        with_target(id, |target| {
+            let (width, height) = (target.width() as usize, target.height() as usize);
+            // Rec. 709 luma of the unpremultiplied colour.
+            let luma: Vec<f32> = target.get_data().iter().map(|&pixel| {
+                let [_, r, g, b] = unpremultiply(pixel);
+                0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32
+            }).collect();
+            // Edge pixels are repeated outwards.
+            let at = |x: isize, y: isize| {
+                let x = x.max(0).min(width as isize - 1) as usize;
+                let y = y.max(0).min(height as isize - 1) as usize;
+                luma[y * width + x]
+            };
+            let (white, black) = (premultiply([255, 255, 255, 255]), premultiply([255, 0, 0, 0]));
+            for (i, pixel) in target.get_data_mut().iter_mut().enumerate() {
+                let (x, y) = ((i % width) as isize, (i / width) as isize);
+                let gx = at(x + 1, y - 1) + 2. * at(x + 1, y) + at(x + 1, y + 1)
+                    - at(x - 1, y - 1) - 2. * at(x - 1, y) - at(x - 1, y + 1);
+                let gy = at(x - 1, y + 1) + 2. * at(x, y + 1) + at(x + 1, y + 1)
+                    - at(x - 1, y - 1) - 2. * at(x, y - 1) - at(x + 1, y - 1);
+                *pixel = if (gx * gx + gy * gy).sqrt() > threshold { white } else { black };
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_emboss(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let angle = get_arg_f32(_args, 1)?.to_radians();
+        let (sin, cos) = angle.sin_cos();
+        // Each neighbour is weighted by its projection onto the light
+        // direction, so the kernel sums to zero and flat areas land on the
+        // 128 gray bias.
+        let mut kernel = [0f32; 9];
+        for (i, k) in kernel.iter_mut().enumerate() {
+            let (dx, dy) = ((i % 3) as f32 - 1., (i / 3) as f32 - 1.);
+            *k = dx * cos + dy * sin;
+        }
+        with_target(id, |target| {
+            convolve(target, 3, 3, &kernel, 1.0, 128.0, true);
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_resize(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let width = get_arg_u32(_args, 1)?;
+        let height = get_arg_u32(_args, 2)?;
+        let filter = match get_arg_str(_args, 3)?.as_str() {
+            "nearest" => None,
+            "bilinear" => Some(image::imageops::FilterType::Triangle),
+            "lanczos" => Some(image::imageops::FilterType::Lanczos3),
+            other => return Err(PluginError::InvalidArg(3, format!("unknown filter `{}`", other))),
+        };
+        with_target(id, |target| {
+            *target = match filter {
+                Some(filter) => resize_target(target, width, height, filter)?,
+                None => {
+                    // Integer nearest-neighbour mapping; no resampling library needed.
+                    let mut resized = new_target(width.min(i32::MAX as u32) as i32, height.min(i32::MAX as u32) as i32)?;
+                    let (sw, sh) = (target.width() as u64, target.height() as u64);
+                    let (dw, dh) = (width as u64, height as u64);
+                    let src = target.get_data();
+                    for (i, pixel) in resized.get_data_mut().iter_mut().enumerate() {
+                        let (x, y) = (i as u64 % dw, i as u64 / dw);
+                        *pixel = src[((y * sh / dh) * sw + x * sw / dw) as usize];
+                    }
+                    resized
+                }
+            };
+            // The resized target starts with empty clip and layer stacks.
+            with_depths(id, |depths| *depths = (0, 0));
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_expand_canvas(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let mut insets = [0i32; 4];
+        for (i, inset) in insets.iter_mut().enumerate() {
+            *inset = get_arg_u32(_args, 1 + i)?.min(MAX_TARGET_DIMENSION as u32) as i32;
+        }
+        let [top, right, bottom, left] = insets;
+        let src = get_arg_src(_args, 5, 6)?;
+        with_target(id, |target| {
+            let mut expanded = new_target(target.width() + left + right, target.height() + top + bottom)?;
+            let (w, h) = (expanded.width() as f32, expanded.height() as f32);
+            src.draw(&mut expanded, |expanded, src| expanded.fill_rect(0., 0., w, h, src, &DrawOptions::new()));
+            expanded.draw_image_at(left as f32, top as f32, &Image {
+                width: target.width(),
+                height: target.height(),
+                data: target.get_data()
+            }, &DrawOptions::new());
+            *target = expanded;
+            // The expanded target starts with empty clip and layer stacks.
+            with_depths(id, |depths| *depths = (0, 0));
+            Ok(ok_response())
+        })
+    })
+}
+
+#[derive(Serialize)]
+struct TrimRect {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+}
+
+fn op_dt_trim_transparent(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let threshold = get_arg_u8(_args, 1)? as u32;
+        with_target(id, |target| {
+            let width = target.width() as usize;
+            let (mut x0, mut y0, mut x1, mut y1) = (usize::MAX, usize::MAX, 0, 0);
+            for (i, &pixel) in target.get_data().iter().enumerate() {
+                if pixel >> 24 > threshold {
+                    let (x, y) = (i % width, i / width);
+                    x0 = x0.min(x);
+                    y0 = y0.min(y);
+                    x1 = x1.max(x + 1);
+                    y1 = y1.max(y + 1);
+                }
+            }
+            if x0 >= x1 {
+                return Err(PluginError::Invalid("no pixels are above the alpha threshold".to_string()));
+            }
+            let mut trimmed = DrawTarget::new((x1 - x0) as i32, (y1 - y0) as i32);
+            let src = target.get_data();
+            for (row, dst) in trimmed.get_data_mut().chunks_exact_mut(x1 - x0).enumerate() {
+                let start = (y0 + row) * width + x0;
+                dst.copy_from_slice(&src[start..start + (x1 - x0)]);
+            }
+            *target = trimmed;
+            // The trimmed target starts with empty clip and layer stacks.
+            with_depths(id, |depths| *depths = (0, 0));
+            Ok(value_response(TrimRect { x: x0 as i32, y: y0 as i32, width: (x1 - x0) as u32, height: (y1 - y0) as u32 }))
+        })
+    })
+}
+
+fn op_dt_swap(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id_a = get_arg_u32(_args, 0)?;
+        let id_b = get_arg_u32(_args, 1)?;
+        TARGETS.with(|map| {
+            let mut targets = map.borrow_mut();
+            let size = |t: &DrawTarget| (t.width(), t.height());
+            let a = targets.get(&id_a).map(size).ok_or(PluginError::TargetNotFound(id_a))?;
+            let b = targets.get(&id_b).map(size).ok_or(PluginError::TargetNotFound(id_b))?;
+            if a != b {
+                return Err(PluginError::Invalid(format!(
+                    "DrawTarget sizes {}x{} and {}x{} differ", a.0, a.1, b.0, b.1
+                )));
+            }
+            if id_a != id_b {
+                let target_a = targets.remove(&id_a).unwrap();
+                let target_b = targets.insert(id_b, target_a).unwrap();
+                targets.insert(id_a, target_b);
+                // Clip and layer stacks live inside the targets, so their
+                // depth counters move with them.
+                STACK_DEPTHS.with(|depths| {
+                    let mut depths = depths.borrow_mut();
+                    let depth_a = depths.remove(&id_a).unwrap_or((0, 0));
+                    let depth_b = depths.insert(id_b, depth_a).unwrap_or((0, 0));
+                    depths.insert(id_a, depth_b);
+                });
+                // Metadata describes the pixels, so it follows them as well.
+                METADATA.with(|metadata| {
+                    let mut metadata = metadata.borrow_mut();
+                    let labels_a = metadata.remove(&id_a);
+                    let labels_b = metadata.remove(&id_b);
+                    if let Some(labels) = labels_a {
+                        metadata.insert(id_b, labels);
+                    }
+                    if let Some(labels) = labels_b {
+                        metadata.insert(id_a, labels);
+                    }
+                });
+            }
+            Ok(ok_response())
+        })
+    })
+}
+
+fn op_dt_metadata_set(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let key = get_arg_str(_args, 1)?;
+        let value = get_arg_str(_args, 2)?;
+        with_target(id, |_| Ok(()))?;
+        METADATA.with(|metadata| {
+            metadata.borrow_mut().entry(id).or_default().insert(key, value);
+        });
+        Ok(ok_response())
+    })
+}
+
+fn op_dt_metadata_get(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        let key = get_arg_str(_args, 1)?;
+        with_target(id, |_| Ok(()))?;
+        let value = METADATA.with(|metadata| {
+            metadata.borrow().get(&id).and_then(|entries| entries.get(&key).cloned())
+        });
+        Ok(match value {
+            Some(value) => value_response(value),
+            None => ok_response(),
+        })
+    })
+}
+
+fn op_dt_metadata_get_all(
+    _interface: &mut dyn Interface,
+    _args: &mut [ZeroCopyBuf],
+) -> Op {
+    run_op(|| {
+        let id = get_arg_u32(_args, 0)?;
+        with_target(id, |_| Ok(()))?;
+        let entries = METADATA.with(|metadata| {
+            metadata.borrow().get(&id).cloned().unwrap_or_default()
+        });
+        Ok(value_response(entries))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(h: &[f64; 9], x: f32, y: f32) -> (f64, f64) {
+        let (x, y) = (x as f64, y as f64);
+        let w = h[6] * x + h[7] * y + h[8];
+        ((h[0] * x + h[1] * y + h[2]) / w, (h[3] * x + h[4] * y + h[5]) / w)
+    }
+
+    #[test]
+    fn homography_identity() {
+        let square = [0., 0., 1., 0., 1., 1., 0., 1.];
+        let h = homography(&square, &square).unwrap();
+        for (v, e) in h.iter().zip([1., 0., 0., 0., 1., 0., 0., 0., 1.].iter()) {
+            assert!((v - e).abs() < 1e-9, "{:?}", h);
+        }
+    }
+
+    #[test]
+    fn homography_square_to_trapezoid() {
+        let square = [0., 0., 1., 0., 1., 1., 0., 1.];
+        let trapezoid = [0., 0., 4., 0., 3., 2., 1., 2.];
+        let forward = homography(&square, &trapezoid).unwrap();
+        let back = homography(&trapezoid, &square).unwrap();
+        for i in 0..4 {
+            let (u, v) = project(&forward, square[i * 2], square[i * 2 + 1]);
+            assert!((u - trapezoid[i * 2] as f64).abs() < 1e-6);
+            assert!((v - trapezoid[i * 2 + 1] as f64).abs() < 1e-6);
+            let (x, y) = project(&back, u as f32, v as f32);
+            assert!((x - square[i * 2] as f64).abs() < 1e-6);
+            assert!((y - square[i * 2 + 1] as f64).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn homography_rejects_bad_quads() {
+        let square = [0., 0., 1., 0., 1., 1., 0., 1.];
+        assert!(homography(&square, &[0., 0., 0., 0., 0., 0., 0., 0.]).is_none());
+        assert!(homography(&square, &[f32::NAN, 0., 1., 0., 1., 1., 0., 1.]).is_none());
+    }
+
+    #[test]
+    fn reverse_path_round_trips() {
+        let p = |x, y| Point::new(x, y);
+        let ops = vec![
+            PathOp::MoveTo(p(0., 0.)),
+            PathOp::LineTo(p(4., 0.)),
+            PathOp::QuadTo(p(6., 2.), p(4., 4.)),
+            PathOp::CubicTo(p(3., 5.), p(1., 5.), p(0., 4.)),
+            PathOp::Close,
+            PathOp::MoveTo(p(10., 10.)),
+            PathOp::LineTo(p(12., 10.)),
+        ];
+        let path = Path { ops: ops.clone(), winding: Winding::NonZero };
+        let reversed = reverse_path(&path);
+        assert_eq!(reversed.ops[0], PathOp::MoveTo(p(12., 10.)));
+        assert_eq!(reversed.ops.iter().filter(|op| **op == PathOp::Close).count(), 1);
+        assert_eq!(reversed.ops.last(), Some(&PathOp::Close));
+        assert_eq!(reverse_path(&reversed).ops, ops);
+    }
+
+    #[test]
+    fn offset_polygon_grows_square() {
+        let d = 0.25;
+        let square = [Point::new(0., 0.), Point::new(1., 0.), Point::new(1., 1.), Point::new(0., 1.)];
+        let mut reversed = square;
+        reversed.reverse();
+        for points in [square, reversed].iter() {
+            let offset = offset_polygon(points, d);
+            assert_eq!(offset.len(), 4);
+            for (o, p) in offset.iter().zip(points.iter()) {
+                let expect = |v: f32| if v > 0.5 { 1. + d } else { -d };
+                assert!((o.x - expect(p.x)).abs() < 1e-5, "{:?}", offset);
+                assert!((o.y - expect(p.y)).abs() < 1e-5, "{:?}", offset);
+            }
+        }
+    }
+
+    #[test]
+    fn png_idat_concatenates_chunks() {
+        let chunk = |name: &[u8; 4], data: &[u8]| {
+            let mut out = (data.len() as u32).to_be_bytes().to_vec();
+            out.extend_from_slice(name);
+            out.extend_from_slice(data);
+            // png_idat doesn't check CRCs.
+            out.extend_from_slice(&[0; 4]);
+            out
+        };
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(chunk(b"IHDR", &[0; 13]));
+        png.extend(chunk(b"IDAT", b"abc"));
+        png.extend(chunk(b"tEXt", b"skip"));
+        png.extend(chunk(b"IDAT", b"de"));
+        png.extend(chunk(b"IEND", &[]));
+        assert_eq!(png_idat(&png), b"abcde");
+    }
+
+    #[test]
+    fn encode_png_with_level_round_trips() {
+        let (width, height) = (37u32, 23u32);
+        let rgba: Vec<u8> = (0..width * height * 4).map(|i| (i * 7 % 251 + i / 97) as u8).collect();
+        for &level in [0u8, 5, 9].iter() {
+            let png = encode_png_with_level(width, height, &rgba, level).unwrap();
+            let (info, mut reader) = png::Decoder::new(&png[..]).read_info().unwrap();
+            assert_eq!((info.width, info.height), (width, height));
+            let mut pixels = vec![0; info.buffer_size()];
+            reader.next_frame(&mut pixels).unwrap();
+            assert_eq!(pixels, rgba, "level {}", level);
+        }
+    }
+
+    #[test]
+    fn zlib_stored_splits_blocks() {
+        let data: Vec<u8> = (0..0x1_0000 + 123u32).map(|i| (i * 31 % 251) as u8).collect();
+        let stream = zlib_stored(&data);
+        assert_eq!(u16::from_be_bytes([stream[0], stream[1]]) % 31, 0);
+        let mut pos = 2;
+        let mut blocks = 0;
+        let mut inflated = Vec::new();
+        loop {
+            let header = stream[pos];
+            assert_eq!(header >> 1, 0, "only stored blocks are expected");
+            let len = u16::from_le_bytes([stream[pos + 1], stream[pos + 2]]);
+            let nlen = u16::from_le_bytes([stream[pos + 3], stream[pos + 4]]);
+            assert_eq!(nlen, !len);
+            inflated.extend_from_slice(&stream[pos + 5..pos + 5 + len as usize]);
+            pos += 5 + len as usize;
+            blocks += 1;
+            if header & 1 == 1 {
+                break;
+            }
+        }
+        assert_eq!(blocks, 2);
+        assert_eq!(inflated, data);
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in &data {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        assert_eq!(stream[pos..], ((b << 16) | a).to_be_bytes());
+    }
+
+    #[test]
+    fn quantize_median_cut_limits_palette() {
+        // 1024 distinct opaque colours followed by a transparent pixel.
+        let mut rgba = Vec::new();
+        for i in 0..1024u32 {
+            rgba.extend_from_slice(&[(i % 32 * 8) as u8, (i / 32 * 8) as u8, (i % 7 * 36) as u8, 255]);
+        }
+        rgba.extend_from_slice(&[255, 255, 255, 0]);
+        let (palette, indices, transparent) = quantize_median_cut(&rgba);
+        assert_eq!(palette.len() % 3, 0);
+        assert!(palette.len() / 3 <= 256);
+        assert_eq!(transparent as usize, palette.len() / 3 - 1);
+        assert_eq!(indices.len(), 1025);
+        assert_eq!(indices[1024], transparent);
+        assert!(indices[..1024].iter().all(|&i| i != transparent));
+    }
+}